@@ -1,18 +1,53 @@
 mod r2deob;
 
+use std::env;
+
+// r2deob <binary> [name regex] tries every function of the binary, without arguments the calc example runs
 fn main() {
+	let args: Vec<String> = env::args().collect();
+	if let Some(path) = args.get(1) {
+		let mut config = r2deob::batch::BatchConfig::new(path);
+		config.filter = args.get(2).cloned();
+		match r2deob::batch::run(&config) {
+			Ok(results) => print!("{}", r2deob::batch::summary(&results)),
+			Err(err) => eprintln!("{}", err),
+		}
+		return
+	}
+
 	let target = r2deob::engine::FcnConfig {
 		path: "/home/cyrill/r2deob/calc".to_string(),
+		raw: None,
 		loc: "sym.calc".to_string(),
-		len: "12".to_string(),
+		analysis: r2deob::engine::Analysis::Aaa,
+		r2_script: None,
+		snapshot: None,
+		stop: r2deob::engine::StopCondition::Ret,
+		max_steps: 64,
+		check_determinism: true,
+		n_tracers: 4,
 		input_regs: vec!["esi".to_string(),"edi".to_string()],
-		output_reg: "rax".to_string()
+		input_strategy: Default::default(),
+		seed: None,
+		call_stubs: Default::default(),
+		output_regs: vec!["eax".to_string()],
+		probes: Vec::new(),
 	};
 
-	if let Ok(mut session) = r2deob::engine::Session::init(target) {
-		for _ in 0..16 {
-			let _result = session.add_trace();
-		};
-		session.deobfuscate(r2deob::engine::Synthesiser::Tree);
+	let mut session = match r2deob::engine::Session::<r2deob::trace_esil::EsilTracer>::init(target) {
+		Ok(session) => session,
+		Err(err) => return eprintln!("{}", err),
+	};
+	for result in session.add_traces(16) {
+		if let Err(err) = result {
+			eprintln!("{}", err);
+		}
+	};
+	println!("seed: {}", session.seed());
+	if !session.rejected().is_empty() {
+		println!("rejected {} traces", session.rejected().len());
+	}
+	if let Err(err) = session.deobfuscate(r2deob::engine::Synthesiser::Tree(Default::default())) {
+		eprintln!("{}", err);
 	}
 }
@@ -8,6 +8,8 @@ use super::{
 #[derive(Debug, Clone)]
 pub enum Expression {
 	Terminal(String),
+	// None until the synthesiser solved it, see Expression::fill
+	Constant(Option<BaseT>),
 	NonTerminal,
 	Operation(Operator, Box<Expression>, Box<Expression>)
 }
@@ -16,6 +18,7 @@ impl ::std::fmt::Display for Expression {
     fn fmt(&self, w: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
 		match self {
 			Expression::Terminal(x) => write!(w, "{}", x),
+			Expression::Constant(value) => write!(w, "{}", constant_notation(value)),
 			Expression::NonTerminal => write!(w, "U"),
 			Expression::Operation(op, a, b) => write!(w, "({} {} {})", op, a, b)
 		}
@@ -25,25 +28,69 @@ impl ::std::fmt::Display for Expression {
 impl Expression {
 	pub fn math_notation(&self) -> String {
 		match self {
-			Expression::Terminal(x) => return x.clone(),
-			Expression::NonTerminal => return "U".to_string(),
-			Expression::Operation(op, a, b) => return format!("({} {} {})", Expression::math_notation(a), op, Expression::math_notation(b))
+			Expression::Terminal(x) => x.clone(),
+			Expression::Constant(value) => constant_notation(value),
+			Expression::NonTerminal => "U".to_string(),
+			Expression::Operation(op, a, b) => format!("({} {} {})", Expression::math_notation(a), op, Expression::math_notation(b))
 		}
 	}
 
-	//pub fn is_finite(&self) -> bool {
-	//	match self {
-	//		Expression::NonTerminal => return false,
-	//		Expression::Operation(_, a, b) => {
-	//			return Expression::is_finite(&mut *a.clone()) & Expression::is_finite(&mut *b.clone())
-	//		},
-	//		_ => return true
-	//	}
-	//}
+	// Levels of the tree, a single terminal has depth 1
+	pub fn depth(&self) -> usize {
+		match self {
+			Expression::Operation(_, a, b) => 1 + ::std::cmp::max(a.depth(), b.depth()),
+			_ => 1,
+		}
+	}
+
+	// Terminals, non-terminals and operations
+	pub fn size(&self) -> usize {
+		match self {
+			Expression::Operation(_, a, b) => 1 + a.size() + b.size(),
+			_ => 1,
+		}
+	}
+
+	// No non-terminals left, constants may still be unsolved
+	pub fn is_finite(&self) -> bool {
+		match self {
+			Expression::NonTerminal => false,
+			Expression::Operation(_, a, b) => a.is_finite() && b.is_finite(),
+			_ => true,
+		}
+	}
+
+	// Unsolved constants, named c0, c1, .. from left to right in a model
+	pub fn constants(&self) -> usize {
+		match self {
+			Expression::Constant(None) => 1,
+			Expression::Operation(_, a, b) => a.constants() + b.constants(),
+			_ => 0,
+		}
+	}
+
+	// Set the unsolved constants from left to right
+	pub fn fill(&self, values: &[BaseT]) -> Expression {
+		let mut values = values.iter();
+		self.fill_from(&mut values)
+	}
+
+	fn fill_from(&self, values: &mut ::std::slice::Iter<BaseT>) -> Expression {
+		match self {
+			Expression::Constant(None) => Expression::Constant(values.next().cloned()),
+			Expression::Operation(op, a, b) => {
+				let a = a.fill_from(values);
+				Expression::Operation(*op, Box::new(a), Box::new(b.fill_from(values)))
+			},
+			other => other.clone(),
+		}
+	}
 	
+	// None for non-terminals and unsolved constants
 	pub fn eval(&self, input: &HashMap<String,Vec<BaseT>>) -> Option<Vec<BaseT>> {
 		match &self {
-			Expression::Terminal(x) => return parse_registers(&x, input),
+			Expression::Terminal(x) => parse_registers(x, input),
+			Expression::Constant(Some(value)) => input.values().next().map(|column| vec![*value; column.len()]),
 			Expression::Operation(op, a, b) => {
 				let x: Vec<BaseT>;
 				if let Some(value) = Expression::eval(a, input) {
@@ -53,9 +100,9 @@ impl Expression {
 				if let Some(value) = Expression::eval(b, input) {
 					y = value;
 				} else { return None }
-				return op.perform(&x[..], &y[..])
+				op.perform(&x[..], &y[..])
 			},
-			_ => return None
+			_ => None
 		}
 	}
 
@@ -74,6 +121,16 @@ impl Expression {
 		result
 	}
 
+	// A constant and every operator with a constant on either side
+	pub fn constant_combinations(operators: &Vec<Operator>) -> Vec<Expression> {
+		let mut result = vec![Expression::Constant(None)];
+		for op in operators {
+			result.push(Expression::Operation(*op, Box::new(Expression::Constant(None)), Box::new(Expression::NonTerminal)));
+			result.push(Expression::Operation(*op, Box::new(Expression::NonTerminal), Box::new(Expression::Constant(None))));
+		}
+		result
+	}
+
 	pub fn derive(&self, derivates: &Vec<Expression>) -> Vec<Expression> {
 		let mut result: Vec<Expression> = Vec::new();
 		match &self {
@@ -94,6 +151,15 @@ impl Expression {
 	}
 }
 
+// Small constants in decimal, everything else in hex
+fn constant_notation(value: &Option<BaseT>) -> String {
+	match value {
+		Some(value) if *value > -0x100 && *value < 0x100 => value.to_string(),
+		Some(value) => format!("0x{:x}", value),
+		None => "c".to_string(),
+	}
+}
+
 fn parse_registers(register: &String, inputs: &HashMap<String,Vec<BaseT>>) -> Option<Vec<BaseT>> {
 	// could maybe left out for performance
 	if !inputs.contains_key(register) {
@@ -108,6 +174,46 @@ fn test_derive() {
 	let _ = Expression::derive(&mut Expression::NonTerminal, &combinations);
 }
 
+#[test]
+fn test_depth_size() {
+	let ast = Expression::Operation(
+		Operator::Add,
+		Box::new(Expression::Terminal("rax".to_string())),
+		Box::new(Expression::Operation(
+			Operator::Sub,
+			Box::new(Expression::NonTerminal),
+			Box::new(Expression::NonTerminal)
+		))
+	);
+	assert_eq!(ast.depth(), 3);
+	assert_eq!(ast.size(), 5);
+	assert_eq!(Expression::NonTerminal.depth(), 1);
+}
+
+#[test]
+fn test_constants() {
+	let ast = Expression::Operation(
+		Operator::Add,
+		Box::new(Expression::Operation(
+			Operator::Mul,
+			Box::new(Expression::Terminal("rax".to_string())),
+			Box::new(Expression::Constant(None))
+		)),
+		Box::new(Expression::Constant(None))
+	);
+	assert_eq!(ast.constants(), 2);
+	assert!(ast.is_finite());
+	let mut inputs: HashMap<String,Vec<BaseT>> = HashMap::new();
+	inputs.insert("rax".to_string(), vec![1,2,3]);
+	assert!(ast.eval(&inputs).is_none());
+	let filled = ast.fill(&[0x9e3779b9, 7]);
+	assert_eq!(filled.constants(), 0);
+	assert_eq!(filled.math_notation(), "((rax * 0x9e3779b9) + 7)");
+	assert_eq!(filled.eval(&inputs).unwrap(), vec![0x9e3779b9 + 7, 2 * 0x9e3779b9 + 7, 3 * 0x9e3779b9 + 7]);
+	assert!(!Expression::Operation(Operator::Add, Box::new(Expression::Constant(Some(-1))), Box::new(Expression::NonTerminal)).is_finite());
+	assert_eq!(Expression::Constant(Some(-1)).math_notation(), "-1");
+}
+
 #[test]
 fn test_format() {
 	let ast = Expression::Operation(
@@ -0,0 +1,288 @@
+// Run the whole pipeline on every function of a binary and summarise the results
+extern crate regex;
+use regex::Regex;
+
+use std::time::{
+	Duration,
+	Instant,
+};
+
+use super::{
+	engine::{
+		infer_io_with,
+		r2_cmdj,
+		spawn_r2,
+		Analysis,
+		FcnConfig,
+		R2Script,
+		Session,
+		Synthesiser,
+	},
+	reg_profile::RegProfile,
+	synth_tree,
+	trace_esil::EsilTracer,
+	R2Error,
+};
+
+pub struct BatchConfig {
+	pub path: String,
+	pub analysis: Analysis,
+	pub r2_script: Option<R2Script>,
+	// Only functions with a matching name
+	pub filter: Option<String>,
+	// Inclusive size bounds in bytes
+	pub min_size: u64,
+	pub max_size: u64,
+	// Used if calling convention inference fails or finds no inputs,
+	// empty means A0, A1 and R0 of the register profile
+	pub default_inputs: Vec<String>,
+	pub default_outputs: Vec<String>,
+	pub n_traces: usize,
+	// r2 instances tracing in parallel per function
+	pub n_tracers: usize,
+	pub max_steps: usize,
+	pub seed: Option<u64>,
+	// Budget per function for tracing and the synthesis of all its outputs
+	pub time_budget: Duration,
+}
+
+// Outcome for one function, error is set if it failed before synthesis
+pub struct BatchResult {
+	pub name: String,
+	pub addr: u64,
+	pub inputs: Vec<String>,
+	pub outputs: Vec<String>,
+	pub results: Vec<(String, Option<(String, f32)>)>,
+	pub error: Option<String>,
+}
+
+impl BatchConfig {
+	pub fn new(path: &str) -> BatchConfig {
+		BatchConfig {
+			path: path.to_string(),
+			analysis: Default::default(),
+			r2_script: None,
+			filter: None,
+			min_size: 0,
+			max_size: u64::MAX,
+			default_inputs: Vec::new(),
+			default_outputs: Vec::new(),
+			n_traces: 16,
+			n_tracers: 4,
+			max_steps: 4096,
+			seed: None,
+			time_budget: Duration::from_secs(10),
+		}
+	}
+}
+
+pub fn run(config: &BatchConfig) -> Result<Vec<BatchResult>, R2Error> {
+	let filter = match &config.filter {
+		Some(filter) => Some(Regex::new(filter).map_err(|e| R2Error::Config(e.to_string()))?),
+		None => None,
+	};
+
+	// One analysed r2 session for listing and I/O inference, every function gets its own tracers
+	let mut template = FcnConfig::new(&config.path, "");
+	template.analysis = config.analysis.clone();
+	template.r2_script = config.r2_script.clone();
+	template.max_steps = config.max_steps;
+	template.n_tracers = config.n_tracers;
+	template.seed = config.seed;
+	let mut r2 = spawn_r2(&template)?;
+	let fcns = r2_cmdj(&mut r2, "aflj")?;
+	let profile = RegProfile::load(&mut r2)?;
+	let default_inputs = if config.default_inputs.is_empty() { alias_regs(&profile, &["A0", "A1"]) }
+	else { config.default_inputs.clone() };
+	let default_outputs = if config.default_outputs.is_empty() { alias_regs(&profile, &["R0"]) }
+	else { config.default_outputs.clone() };
+
+	let mut targets = Vec::new();
+	for fcn in fcns.as_array().unwrap_or(&Vec::new()) {
+		let name = if let Some(name) = fcn["name"].as_str() { name }
+		else { continue };
+		let addr = fcn["offset"].as_u64().unwrap_or(0);
+		let size = fcn["size"].as_u64().unwrap_or(0);
+		if size < config.min_size || size > config.max_size {
+			continue
+		}
+		if let Some(regex) = &filter {
+			if !regex.is_match(name) { continue }
+		}
+		let mut target = template.clone();
+		target.loc = name.to_string();
+		let mut target = match infer_io_with(&mut r2, target.clone()) {
+			Ok(inferred) => inferred,
+			Err(_) => {
+				target.output_regs = default_outputs.clone();
+				target
+			},
+		};
+		// Without known arguments the fcn may still read the argument registers
+		if target.input_regs.is_empty() {
+			target.input_regs = default_inputs.clone();
+		}
+		// The tracers only analyse their fcn, the full analysis would eat the time budget
+		target.loc = format!("0x{:x}", addr);
+		target.analysis = Analysis::None;
+		target.r2_script = Some(fcn_script(&config.r2_script, addr));
+		targets.push((name.to_string(), addr, target));
+	}
+	r2.close();
+
+	Ok(targets.into_iter().map(|(name, addr, target)| run_fcn(config, name, addr, target)).collect())
+}
+
+// Registers behind the role aliases the profile has
+fn alias_regs(profile: &RegProfile, roles: &[&str]) -> Vec<String> {
+	roles.iter().filter_map(|role| profile.get(role)).map(|reg| reg.name.clone()).collect()
+}
+
+// The init script followed by af on the fcn
+fn fcn_script(script: &Option<R2Script>, addr: u64) -> R2Script {
+	let mut cmds = match script {
+		Some(R2Script::File(path)) => vec![". ".to_string() + path],
+		Some(R2Script::Commands(cmds)) => cmds.clone(),
+		None => Vec::new(),
+	};
+	cmds.push(format!("af @ 0x{:x}", addr));
+	R2Script::Commands(cmds)
+}
+
+// Void functions are reported with "no outputs". Tracing runs in rounds of n_tracers
+// until n_traces are taken or the budget is spent, synthesis gets what is left.
+fn run_fcn(config: &BatchConfig, name: String, addr: u64, target: FcnConfig) -> BatchResult {
+	let start = Instant::now();
+	let mut result = BatchResult {
+		name,
+		addr,
+		inputs: target.input_regs.clone(),
+		outputs: target.output_regs.clone(),
+		results: Vec::new(),
+		error: None,
+	};
+	if target.input_regs.is_empty() || target.output_regs.is_empty() {
+		result.error = Some(if target.input_regs.is_empty() { "no inputs" } else { "no outputs" }.to_string());
+		return result
+	}
+	let mut session = match Session::<EsilTracer>::init(target) {
+		Ok(session) => session,
+		Err(err) => {
+			result.error = Some(err.to_string());
+			return result
+		},
+	};
+	let mut errors = Vec::new();
+	let mut n_traced = 0;
+	while n_traced < config.n_traces && start.elapsed() < config.time_budget {
+		let n = ::std::cmp::min(::std::cmp::max(config.n_tracers, 1), config.n_traces - n_traced);
+		errors.extend(session.add_traces(n).into_iter().filter_map(|result| result.err()));
+		n_traced += n;
+	}
+	if errors.len() == n_traced {
+		result.error = Some(errors.first().map_or("no traces".to_string(), |e| e.to_string()));
+		return result
+	}
+	let remaining = match config.time_budget.checked_sub(start.elapsed()) {
+		Some(remaining) if remaining > Duration::from_millis(0) => remaining,
+		_ => {
+			result.error = Some("time budget spent on tracing".to_string());
+			return result
+		},
+	};
+	let settings = synth_tree::Settings {
+		timeout: Some(remaining / result.outputs.len() as u32),
+		..Default::default()
+	};
+	match session.synthesize(Synthesiser::Tree(settings)) {
+		Ok(results) => result.results = results.into_iter()
+			.map(|(reg, best)| (reg, best.expression.as_ref().map(|expression| (expression.math_notation(), best.score))))
+			.collect(),
+		Err(err) => result.error = Some(err.to_string()),
+	}
+	result
+}
+
+// One row per function output: function, I/O, best expression, score and status
+pub fn summary(results: &[BatchResult]) -> String {
+	let mut rows = vec![["function".to_string(), "io".to_string(), "expression".to_string(), "score".to_string(), "status".to_string()]];
+	for fcn in results.iter() {
+		let name = format!("{} @ 0x{:x}", fcn.name, fcn.addr);
+		let io = format!("{} -> {}", fcn.inputs.join(", "), fcn.outputs.join(", "));
+		if let Some(err) = &fcn.error {
+			rows.push([name, io, "-".to_string(), "-".to_string(), err.clone()]);
+			continue
+		}
+		for (reg, best) in fcn.results.iter() {
+			rows.push(match best {
+				Some((expression, score)) => [name.clone(), io.clone(), format!("{} = {}", reg, expression), format!("{:.3}", score),
+					if *score == 1.0 { "ok".to_string() } else { "no exact match".to_string() }],
+				None => [name.clone(), io.clone(), format!("{} = ?", reg), "-".to_string(), "no candidates".to_string()],
+			});
+		}
+	}
+	let mut widths = [0usize; 5];
+	for row in rows.iter() {
+		for (width, cell) in widths.iter_mut().zip(row.iter()) {
+			*width = ::std::cmp::max(*width, cell.len());
+		}
+	}
+	let mut table = String::new();
+	for row in rows.iter() {
+		let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, width)| format!("{:1$}", cell, width)).collect();
+		table += cells.join(" | ").trim_end();
+		table += "\n";
+	}
+	table
+}
+
+#[test]
+fn test_alias_regs() {
+	let json = serde_json::from_str(r#"{
+		"alias_info": [{"role_str": "A0", "reg": "r0"}, {"role_str": "A1", "reg": "r1"}, {"role_str": "R0", "reg": "r0"}],
+		"reg_info": [
+			{"type_str": "gpr", "name": "r0", "size": 32, "offset": 0},
+			{"type_str": "gpr", "name": "r1", "size": 32, "offset": 32}
+		]
+	}"#).unwrap();
+	let profile = RegProfile::parse(&json).unwrap();
+	assert_eq!(alias_regs(&profile, &["A0", "A1"]), vec!["r0", "r1"]);
+	assert_eq!(alias_regs(&profile, &["R0", "A2"]), vec!["r0"]);
+}
+
+#[test]
+fn test_batch_summary() {
+	let results = vec![
+		BatchResult {
+			name: "sym.calc".to_string(),
+			addr: 0x1139,
+			inputs: vec!["edi".to_string(), "esi".to_string()],
+			outputs: vec!["rax".to_string()],
+			results: vec![("rax".to_string(), Some(("(edi + (esi + esi))".to_string(), 1.0)))],
+			error: None,
+		},
+		BatchResult {
+			name: "main".to_string(),
+			addr: 0x1160,
+			inputs: Vec::new(),
+			outputs: vec!["rax".to_string()],
+			results: Vec::new(),
+			error: Some("no inputs".to_string()),
+		},
+		BatchResult {
+			name: "sym.init".to_string(),
+			addr: 0x1180,
+			inputs: vec!["rdi".to_string()],
+			outputs: Vec::new(),
+			results: Vec::new(),
+			error: Some("no outputs".to_string()),
+		},
+	];
+	let table = summary(&results);
+	let lines: Vec<&str> = table.lines().collect();
+	assert_eq!(lines.len(), 4);
+	assert!(lines[1].starts_with("sym.calc @ 0x1139 | edi, esi -> rax | rax = (edi + (esi + esi)) | 1.000 | ok"));
+	assert!(lines[2].ends_with("| -     | no inputs"));
+	assert!(lines[3].starts_with("sym.init @ 0x1180 | rdi ->") && lines[3].ends_with("no outputs"));
+	assert_eq!(lines[0].find("| io"), lines[1].find("| edi"));
+}
@@ -12,6 +12,14 @@ pub enum Operator {
 	Sub,
 	Mul,
 	Div,
+	// Comparisons and logic, evaluate to 0 or 1 on boolean operands
+	Eq,
+	Ne,
+	Slt,
+	Ult,
+	And,
+	Or,
+	Xor,
 }
 
 impl ::std::fmt::Display for Operator {
@@ -21,6 +29,13 @@ impl ::std::fmt::Display for Operator {
 			Operator::Sub => "-",
 			Operator::Mul => "*",
 			Operator::Div => "/",
+			Operator::Eq => "==",
+			Operator::Ne => "!=",
+			Operator::Slt => "<s",
+			Operator::Ult => "<u",
+			Operator::And => "&",
+			Operator::Or => "|",
+			Operator::Xor => "^",
 		})
 	}
 }
@@ -28,12 +43,32 @@ impl ::std::fmt::Display for Operator {
 impl Operator {
 	pub fn perform(&self, a: &[BaseT], b: &[BaseT]) -> Option<Vec<BaseT>> {
 		match self {
-			Operator::Add => return Some(Operator::simd_add(&a[..], &b[..])),
-			Operator::Sub => return Some(Operator::simd_sub(&a[..], &b[..])),
-			Operator::Mul => return Some(Operator::simd_mul(&a[..], &b[..])),
-			Operator::Div => return Some(Operator::simd_div(&a[..], &b[..])),
+			Operator::Add => Some(Operator::simd_add(a, b)),
+			Operator::Sub => Some(Operator::simd_sub(a, b)),
+			Operator::Mul => Some(Operator::simd_mul(a, b)),
+			Operator::Div => Some(Operator::simd_div(a, b)),
+			Operator::Eq => Some(zip_with(a, b, |x, y| (x == y) as BaseT)),
+			Operator::Ne => Some(zip_with(a, b, |x, y| (x != y) as BaseT)),
+			Operator::Slt => Some(zip_with(a, b, |x, y| (x < y) as BaseT)),
+			Operator::Ult => Some(zip_with(a, b, |x, y| ((x as u64) < (y as u64)) as BaseT)),
+			Operator::And => Some(zip_with(a, b, |x, y| x & y)),
+			Operator::Or => Some(zip_with(a, b, |x, y| x | y)),
+			Operator::Xor => Some(zip_with(a, b, |x, y| x ^ y)),
 		}
 	}
+
+	pub fn arithmetic() -> Vec<Operator> {
+		vec![Operator::Add, Operator::Sub, Operator::Mul, Operator::Div]
+	}
+
+	// Operators for flag outputs, inputs have to be sign extended for Slt and Ult to hold
+	pub fn boolean() -> Vec<Operator> {
+		vec![Operator::Eq, Operator::Ne, Operator::Slt, Operator::Ult, Operator::And, Operator::Or, Operator::Xor]
+	}
+}
+
+fn zip_with<F: Fn(BaseT, BaseT) -> BaseT>(a: &[BaseT], b: &[BaseT], f: F) -> Vec<BaseT> {
+	a.iter().zip(b).map(|(x, y)| f(*x, *y)).collect()
 }
 
 pub trait SimdOperator<T> {
@@ -58,6 +93,10 @@ impl SimdOperator<i64> for Operator {
 			.map(|(a, b)| a + b) {
 			elem.write_to_slice_unaligned(&mut result_chunk.next().unwrap());
 		}
+		// Lanes past the last full vector
+		for n in a.len() / 8 * 8..a.len() {
+			result[n] = a[n].wrapping_add(b[n]);
+		}
 		result
 	}
 
@@ -75,6 +114,10 @@ impl SimdOperator<i64> for Operator {
 			.map(|(a, b)| a - b) {
 			elem.write_to_slice_unaligned(&mut result_chunk.next().unwrap());
 		}
+		// Lanes past the last full vector
+		for n in a.len() / 8 * 8..a.len() {
+			result[n] = a[n].wrapping_sub(b[n]);
+		}
 		result
 	}
 
@@ -92,6 +135,10 @@ impl SimdOperator<i64> for Operator {
 			.map(|(a, b)| a * b) {
 			elem.write_to_slice_unaligned(&mut result_chunk.next().unwrap());
 		}
+		// Lanes past the last full vector
+		for n in a.len() / 8 * 8..a.len() {
+			result[n] = a[n].wrapping_mul(b[n]);
+		}
 		result
 	}
 
@@ -130,6 +177,10 @@ impl SimdOperator<i32> for Operator {
 			.map(|(a, b)| a + b) {
 			elem.write_to_slice_unaligned(&mut result_chunk.next().unwrap());
 		}
+		// Lanes past the last full vector
+		for n in a.len() / 16 * 16..a.len() {
+			result[n] = a[n].wrapping_add(b[n]);
+		}
 		result
 	}
 
@@ -147,6 +198,10 @@ impl SimdOperator<i32> for Operator {
 			.map(|(a, b)| a - b) {
 			elem.write_to_slice_unaligned(&mut result_chunk.next().unwrap());
 		}
+		// Lanes past the last full vector
+		for n in a.len() / 16 * 16..a.len() {
+			result[n] = a[n].wrapping_sub(b[n]);
+		}
 		result
 	}
 
@@ -164,6 +219,10 @@ impl SimdOperator<i32> for Operator {
 			.map(|(a, b)| a * b) {
 			elem.write_to_slice_unaligned(&mut result_chunk.next().unwrap());
 		}
+		// Lanes past the last full vector
+		for n in a.len() / 16 * 16..a.len() {
+			result[n] = a[n].wrapping_mul(b[n]);
+		}
 		result
 	}
 
@@ -204,3 +263,30 @@ fn test_sisd_div_i64() {
 	let result = Operator::sisd_div(&[1i64,2i64,3i64,4i64,5i64,6i64,7i64,8i64], &[1i64,2i64,3i64,4i64,5i64,6i64,7i64,8i64]);
 	assert!(result == [1,1,1,1,1,1,1,1], format!("Test result was: {:?}", result));
 }
+
+#[test]
+fn test_simd_remainder_i64() {
+	let result = Operator::simd_add(&[1i64,2,3,4,5,6,7,8,9,10], &[1i64,1,1,1,1,1,1,1,1,BaseT::max_value()]);
+	assert_eq!(result[8..], [10, BaseT::min_value() + 9]);
+	assert_eq!(Operator::simd_mul(&[3i64], &[5i64]), vec![15]);
+}
+
+#[test]
+fn test_simd_remainder_i32() {
+	let a: Vec<i32> = (1..19).collect();
+	let result = Operator::simd_sub(&a, &[1i32; 18]);
+	assert_eq!(result[16..], [16, 17]);
+	assert_eq!(Operator::simd_add(&[i32::max_value()], &[1i32]), vec![i32::min_value()]);
+	assert_eq!(Operator::simd_mul(&[3i32], &[5i32]), vec![15]);
+}
+
+#[test]
+fn test_boolean_ops() {
+	let a = [1, -1, 5, 0];
+	let b = [1, 2, 3, 0];
+	assert_eq!(Operator::Eq.perform(&a, &b), Some(vec![1, 0, 0, 1]));
+	assert_eq!(Operator::Ne.perform(&a, &b), Some(vec![0, 1, 1, 0]));
+	assert_eq!(Operator::Slt.perform(&a, &b), Some(vec![0, 1, 0, 0]));
+	assert_eq!(Operator::Ult.perform(&a, &b), Some(vec![0, 0, 0, 0]));
+	assert_eq!(Operator::Xor.perform(&[0, 1, 1], &[1, 1, 0]), Some(vec![1, 0, 1]));
+}
@@ -2,124 +2,849 @@ extern crate r2pipe;
 use r2pipe::R2Pipe;
 
 extern crate rand;
-use rand::prelude::random;
+use rand::{
+	random,
+	rngs::StdRng,
+};
+
+extern crate rayon;
+use rayon::prelude::*;
+
+extern crate serde;
+use serde::{
+	Deserialize,
+	Serialize,
+};
 
 use super::{
 	synth_tree,
+	synth_tree::SynthesisResult,
+	location::Location,
+	snapshot::Snapshot,
+	input::{
+		mask,
+		sign_extend,
+		InputStrategy,
+	},
+	trace_esil::EsilTracer,
+	trace_file::TraceFile,
+	seeded_rng,
 	R2Error,
 	BaseT,
 };
 
-use std::collections::HashMap;
+use std::collections::{
+	HashMap,
+	HashSet,
+};
+use std::fmt;
+use std::sync::Arc;
 
 pub enum Synthesiser {
-	Tree,
+	Tree(synth_tree::Settings),
+}
+
+// Backend that runs the target for one set of input values and reads the outputs
+pub trait Tracer: Sized + Send {
+	fn init(fcn: &FcnConfig, inputs: &[Location], outputs: &[Location]) -> Result<Self, R2Error>;
+	fn trace(&mut self, inputs: &[Location], values: &[BaseT], outputs: &[Location]) -> Result<Trace, R2Error>;
+	// Width in bytes, tracers that know the register profile override this
+	fn width(&self, location: &Location) -> usize {
+		location.width()
+	}
+	// Single bit registers like zf or cf
+	fn is_flag(&self, _location: &Location) -> bool {
+		false
+	}
 }
 
-pub struct Session {
-	r2: R2Pipe,
+pub struct Session<T: Tracer = EsilTracer> {
+	tracers: Vec<T>,
 	fcn_config: FcnConfig,
+	inputs: Vec<Location>,
+	outputs: Vec<Location>,
+	rng: StdRng,
 	traces: Traces,
+	// Reasons for the traces add_trace threw away
+	rejected: Vec<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FcnConfig {
 	pub path: String,
+	// Code without a container file, path is ignored if set
+	pub raw: Option<RawCode>,
 	pub loc: String,
-	pub len: String,
+	pub analysis: Analysis,
+	// Runs after analysis and before ESIL is initialised
+	pub r2_script: Option<R2Script>,
+	// Memory and registers mapped before every trace, inputs are written on top
+	pub snapshot: Option<Snapshot>,
+	pub stop: StopCondition,
+	pub max_steps: usize,
+	// Trace every input twice and reject traces whose outputs differ
+	pub check_determinism: bool,
+	// Tracer instances, e.g. r2 processes, that add_traces spreads the inputs over
+	pub n_tracers: usize,
+	// Registers or memory locations, see location::Location
 	pub input_regs: Vec<String>,
-	pub output_reg: String
+	pub input_strategy: InputStrategy,
+	// Drives input generation and synthesis, a random one is picked and stored if None
+	pub seed: Option<u64>,
+	// Calls that are skipped instead of emulated, keyed by call target (flag or address, "*" for any call)
+	pub call_stubs: HashMap<String,CallStub>,
+	// Registers, memory locations or pointed-to scratch memory
+	pub output_regs: Vec<String>,
+	// Registers recorded in the middle of the run, each one is an output of its own
+	pub probes: Vec<Probe>,
+}
+
+// Value of reg when emulation first reaches loc (a flag or address), before the instruction there runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Probe {
+	pub loc: String,
+	pub reg: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawCode {
+	pub bytes: Vec<u8>,
+	pub arch: String,
+	pub bits: u32,
+	pub big_endian: bool,
+	pub base: u64,
+	// asm.os, selects the calling convention and syscalls; r2's default if None
+	#[serde(default)]
+	pub os: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Analysis {
+	None,
+	Aa,
+	#[default]
+	Aaa,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum R2Script {
+	File(String),
+	Commands(Vec<String>),
+}
+
+pub type StubFn = dyn Fn(&[BaseT]) -> BaseT + Send + Sync;
+
+// What a skipped call returns. The return value becomes an input terminal named callee@callsite.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum CallStub {
+	Constant(BaseT),
+	// Called with the values of the argument registers, can not be saved to a trace file
+	#[serde(skip)]
+	Closure(Arc<StubFn>),
+	// Built-in model picked by the callee name: strlen, memcpy, abs or rand
+	Libc,
+}
+
+// Where emulation of the target ends; locations can be flags or addresses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StopCondition {
+	Ret,
+	Address(String),
+	Addresses(Vec<String>),
 }
 
+// Which stop condition ended a trace
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StopReason {
+	Ret,
+	Address(u64),
+	MaxSteps,
+}
+
+// Output values of a single run, in the order of the output locations
+#[derive(Debug)]
+pub struct Trace {
+	pub outputs: Vec<BaseT>,
+	pub stop: StopReason,
+	// Return values of stubbed calls, see CallStub
+	pub stubs: Vec<(String,BaseT)>,
+	// One value per probe, None if its location was not reached
+	pub probes: Vec<Option<BaseT>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Traces {
 	pub inputs: HashMap<String,Vec<BaseT>>,
-	pub outputs: Vec<BaseT>,
+	pub outputs: HashMap<String,Vec<BaseT>>,
+	pub stops: Vec<StopReason>,
+	// Width in bytes of every input and output, values are stored masked to it
+	pub widths: HashMap<String,usize>,
+	// Flag inputs and outputs, stored as 0 or 1
+	pub flags: HashSet<String>,
+	// Inputs that hold return values of stubbed calls, in order of first appearance
+	pub stubs: Vec<String>,
 }
 
 impl Traces {
-	pub fn push_strings(&mut self, registers: Vec<String>, input: Vec<String>, output: String) -> Result<(), String> {
+	pub fn push(&mut self, registers: &[String], input: &[BaseT], output_registers: &[String], output: &[BaseT], stop: StopReason) -> Result<(), R2Error> {
 		if registers.len() != input.len() {
-			return Err("expected one input for each register".to_string())
+			return Err(R2Error::Config("expected one input for each register".to_string()))
+		}
+		if output_registers.len() != output.len() {
+			return Err(R2Error::Config("expected one output for each register".to_string()))
 		}
 		for (reg, val) in registers.iter().zip(input) {
-			self.inputs.get_mut(reg).unwrap().push(val.parse().unwrap());
+			let val = self.mask(reg, *val);
+			if let Some(column) = self.inputs.get_mut(reg) { column.push(val) }
+			else { return Err(R2Error::Config(format!("unknown input: {}", reg))) };
+		}
+		for (reg, val) in output_registers.iter().zip(output) {
+			let val = self.mask(reg, *val);
+			if let Some(column) = self.outputs.get_mut(reg) { column.push(val) }
+			else { return Err(R2Error::Config(format!("unknown output: {}", reg))) };
+		}
+		self.stops.push(stop);
+		Ok(())
+	}
+
+	// Add the stub return values of the last pushed trace. Stubs not hit in a trace return 0,
+	// new ones are filled up with 0 for the traces before. A call hit twice keeps its last value.
+	pub fn push_stubs(&mut self, stubs: &[(String,BaseT)]) {
+		let n = self.stops.len();
+		for (name, _) in stubs.iter() {
+			if !self.stubs.contains(name) {
+				self.stubs.push(name.clone());
+				self.inputs.insert(name.clone(), vec![0; n - 1]);
+			}
+		}
+		for name in self.stubs.iter() {
+			let value = stubs.iter().rev().find(|(stub, _)| stub == name).map_or(0, |(_, value)| *value);
+			if let Some(column) = self.inputs.get_mut(name) { column.push(value) }
+		}
+	}
+
+	// Width of an input or output, full width if unknown
+	pub fn width(&self, reg: &str) -> usize {
+		self.widths.get(reg).cloned().unwrap_or(8)
+	}
+
+	fn mask(&self, reg: &str, value: BaseT) -> BaseT {
+		if self.flags.contains(reg) { return value & 1 }
+		(value as u64 & mask(self.width(reg))) as BaseT
+	}
+}
+
+impl FcnConfig {
+	// Defaults for everything but the target, inputs and outputs are empty, see infer_io
+	pub fn new(path: &str, loc: &str) -> FcnConfig {
+		FcnConfig {
+			path: path.to_string(),
+			raw: None,
+			loc: loc.to_string(),
+			analysis: Default::default(),
+			r2_script: None,
+			snapshot: None,
+			stop: StopCondition::Ret,
+			max_steps: 4096,
+			check_determinism: true,
+			n_tracers: 1,
+			input_regs: Vec::new(),
+			input_strategy: Default::default(),
+			seed: None,
+			call_stubs: HashMap::new(),
+			output_regs: Vec::new(),
+			probes: Vec::new(),
+		}
+	}
+
+	// Output registers followed by the probes, the names outputs are stored and reported under
+	pub fn output_names(&self) -> Vec<String> {
+		self.output_regs.iter().cloned().chain(self.probes.iter().map(Probe::name)).collect()
+	}
+}
+
+impl Probe {
+	pub fn new(loc: &str, reg: &str) -> Probe {
+		Probe { loc: loc.to_string(), reg: reg.to_string() }
+	}
+
+	// Output name, e.g. eax@0x1150
+	pub fn name(&self) -> String {
+		format!("{}@{}", self.reg, self.loc)
+	}
+}
+
+impl fmt::Debug for CallStub {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			CallStub::Constant(value) => write!(f, "Constant({})", value),
+			CallStub::Closure(_) => write!(f, "Closure"),
+			CallStub::Libc => write!(f, "Libc"),
+		}
+	}
+}
+
+impl Analysis {
+	pub fn cmd(&self) -> &str {
+		match self {
+			Analysis::None => "",
+			Analysis::Aa => "aa",
+			Analysis::Aaa => "aaa",
+		}
+	}
+}
+
+impl R2Script {
+	pub fn run(&self, r2: &mut R2Pipe) -> Result<(), R2Error> {
+		let cmds = match self {
+			R2Script::File(path) => vec![". ".to_string() + path],
+			R2Script::Commands(cmds) => cmds.clone(),
+		};
+		for cmd in cmds.iter() {
+			r2_cmd(r2, cmd)?;
 		}
-		self.outputs.push(output.parse().unwrap());
 		Ok(())
 	}
 }
 
-impl Session {
-	// Spawn r2pipe, init esil
-	pub fn init(fcn: FcnConfig) -> Result<Session, R2Error> {
-		let mut r2pipe = if let Ok(pipe) = R2Pipe::spawn(&fcn.path, None) { pipe }
-		else { return Err(R2Error::PipeFail) };
+impl RawCode {
+	pub fn from_hex(hex: &str, arch: &str, bits: u32, big_endian: bool, base: u64) -> Result<RawCode, R2Error> {
+		Ok(RawCode {
+			bytes: decode_hex(hex)?,
+			arch: arch.to_string(),
+			bits,
+			big_endian,
+			base,
+			os: None,
+		})
+	}
+
+	pub fn to_hex(&self) -> String {
+		self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+	}
+}
 
-		if let Ok(_) = r2pipe.cmd("aaa;aei;aeim") {} 
-		else { return Err(R2Error::CmdFail) };
+impl<T: Tracer> Session<T> {
+	pub fn init(mut fcn: FcnConfig) -> Result<Session<T>, R2Error> {
+		let seed = fcn.seed.unwrap_or_else(random);
+		fcn.seed = Some(seed);
 
+		let mut locations = Vec::new();
 		let mut inputs = HashMap::new();
 		for register in fcn.input_regs.iter() {
+			match Location::parse(register)? {
+				Location::Pointer(_, _) => return Err(R2Error::Config(format!("pointer inputs are not supported: {}", register))),
+				location => locations.push(location),
+			}
 			inputs.insert(register.to_string(), Vec::new());
 		}
-		
+		let mut output_locations = Vec::new();
+		let mut outputs = HashMap::new();
+		for register in fcn.output_regs.iter() {
+			output_locations.push(Location::parse(register)?);
+			outputs.insert(register.to_string(), Vec::new());
+		}
+		// Pointer outputs overwrite their register at fcn entry, the input would be lost
+		for location in output_locations.iter() {
+			if let Location::Pointer(reg, _) = location {
+				if locations.contains(&Location::Register(reg.clone())) {
+					return Err(R2Error::Config(format!("register of pointer output is also an input: {}", reg)))
+				}
+			}
+		}
+
+		let tracers = (0..::std::cmp::max(fcn.n_tracers, 1)).into_par_iter()
+			.map(|_| T::init(&fcn, &locations, &output_locations))
+			.collect::<Result<Vec<T>, R2Error>>()?;
+		let mut widths = HashMap::new();
+		let mut flags = HashSet::new();
+		for (reg, location) in fcn.input_regs.iter().zip(&locations).chain(fcn.output_regs.iter().zip(&output_locations)) {
+			widths.insert(reg.clone(), tracers[0].width(location));
+			if tracers[0].is_flag(location) {
+				flags.insert(reg.clone());
+			}
+		}
+		for probe in fcn.probes.iter() {
+			let location = Location::Register(probe.reg.clone());
+			widths.insert(probe.name(), tracers[0].width(&location));
+			if tracers[0].is_flag(&location) {
+				flags.insert(probe.name());
+			}
+			outputs.insert(probe.name(), Vec::new());
+		}
+
 		Ok(Session {
-			r2: r2pipe,
+			tracers,
 			fcn_config: fcn,
-			traces: Traces { inputs: inputs.clone(), outputs: Vec::new() }
+			inputs: locations,
+			outputs: output_locations,
+			rng: seeded_rng(seed),
+			traces: Traces { inputs: inputs.clone(), outputs, stops: Vec::new(), widths, flags, stubs: Vec::new() },
+			rejected: Vec::new(),
 		})
 	}
 
-	pub fn add_trace(&mut self) -> Result<String, String> {
-		// Flush old stuff and seek to target fcn
-		let cmd = "aek-;s ".to_string() + &self.fcn_config.loc;
-		let _res = self.r2.cmd(&cmd);
-		// Init esil
-		let cmd = "aei;aeim;aeip";
-		let _res = self.r2.cmd(&cmd);
-		// Set random input
-		let input = get_random_input(self.fcn_config.input_regs.len() as u8);
-		for n in 0..self.fcn_config.input_regs.len() {
-			let cmd = "aer ".to_string() + &self.fcn_config.input_regs.get(n).unwrap()
-				+ &" = ".to_string() + input.get(n).unwrap();
-			let _res = self.r2.cmd(&cmd);
-		}
-		// Run
-		let cmd = self.fcn_config.len.clone() + &"aes".to_string();
-		let _res = self.r2.cmd(&cmd);
-		// Fetch result
-		let reg: &str = &self.fcn_config.output_reg;
-		let output = self.r2.cmdj("aerj")?[reg].to_string();
-		let result = output.clone();
-
-		let registers = self.fcn_config.input_regs.clone();
-		if let Ok(_) = self.traces.push_strings(registers, input, output) { return Ok(result) };
-		Err(String::new())
-	}
-
-	pub fn deobfuscate(self, backend: Synthesiser) {
-		let inputs = self.traces.inputs;
-		let outputs = self.traces.outputs;
-		let registers = self.fcn_config.input_regs.clone();
-		match backend {
-			Synthesiser::Tree => {
-				let mut synthesis = synth_tree::Synthesis::default(&registers);
-				synthesis.synthesize(&inputs, &outputs);
+	pub fn add_trace(&mut self) -> Result<Vec<BaseT>, R2Error> {
+		self.add_traces(1).remove(0)
+	}
+
+	// Generate n inputs up front and trace them in parallel, every tracer takes every n_tracers-th input.
+	// Results are merged in input order, so the traces only depend on the seed and not on the pool size.
+	pub fn add_traces(&mut self, n: usize) -> Vec<Result<Vec<BaseT>, R2Error>> {
+		let widths: Vec<usize> = self.fcn_config.input_regs.iter().map(|reg| self.traces.width(reg)).collect();
+		let (fcn, traces, rng) = (&self.fcn_config, &self.traces, &mut self.rng);
+		let values: Vec<Vec<BaseT>> = (0..n)
+			.map(|_| fcn.input_strategy.generate(rng, &fcn.input_regs, &widths))
+			.map(|input| fcn.input_regs.iter().zip(input).map(|(reg, value)| traces.mask(reg, value)).collect())
+			.collect();
+
+		let n_tracers = self.tracers.len();
+		let (fcn, inputs, outputs) = (&self.fcn_config, &self.inputs, &self.outputs);
+		let mut traces: Vec<(usize, Result<Trace, R2Error>)> = self.tracers.par_iter_mut().enumerate()
+			.flat_map(|(n, tracer)| {
+				values.iter().enumerate().skip(n).step_by(n_tracers)
+					.map(|(i, input)| (i, checked_trace(tracer, fcn, inputs, outputs, input)))
+					.collect::<Vec<_>>()
+			})
+			.collect();
+		traces.sort_by_key(|(i, _)| *i);
+
+		let output_names = self.fcn_config.output_names();
+		traces.into_iter().zip(values.iter()).map(|((_, trace), input)| {
+			match trace {
+				Ok(trace) => {
+					let probes = trace.probes.iter().map(|value| value.unwrap_or(0));
+					let values: Vec<BaseT> = trace.outputs.iter().cloned().chain(probes).collect();
+					self.traces.push(&self.fcn_config.input_regs, input, &output_names, &values, trace.stop)?;
+					self.traces.push_stubs(&trace.stubs);
+					Ok(trace.outputs)
+				},
+				Err(R2Error::Rejected(reason)) => self.reject(input, reason),
+				Err(err) => Err(err),
+			}
+		}).collect()
+	}
+
+	fn reject(&mut self, input: &[BaseT], reason: String) -> Result<Vec<BaseT>, R2Error> {
+		let values: Vec<String> = input.iter().map(|value| format!("0x{:x}", value)).collect();
+		let reason = format!("{} (inputs {})", reason, values.join(", "));
+		self.rejected.push(reason.clone());
+		Err(R2Error::Rejected(reason))
+	}
+
+	// Why each thrown away trace was rejected
+	pub fn rejected(&self) -> &[String] {
+		&self.rejected
+	}
+
+	// Write the traces collected so far, see trace_file::TraceFile
+	pub fn save_traces(&self, path: &str) -> Result<(), R2Error> {
+		TraceFile::new(self.fcn_config.clone(), self.traces.clone())?.save(path)
+	}
+
+	// Continue from saved traces, they have to come from the same binary and have the same I/O
+	pub fn load_traces(&mut self, path: &str) -> Result<(), R2Error> {
+		let file = TraceFile::load(path)?;
+		if !file.matches_binary(&self.fcn_config)? {
+			return Err(R2Error::Io(format!("{}: traces were taken from a different binary", path)))
+		}
+		if file.fcn_config.input_regs != self.fcn_config.input_regs || file.fcn_config.output_names() != self.fcn_config.output_names() {
+			return Err(R2Error::Config(format!("{}: traces have different inputs or outputs", path)))
+		}
+		self.traces = file.traces;
+		Ok(())
+	}
+
+	pub fn seed(&self) -> u64 {
+		self.fcn_config.seed.unwrap_or(0)
+	}
+
+	pub fn deobfuscate(self, backend: Synthesiser) -> Result<Vec<(String, SynthesisResult)>, R2Error> {
+		deobfuscate(&self.fcn_config, &self.traces, backend)
+	}
+
+	pub fn synthesize(&self, backend: Synthesiser) -> Result<Vec<(String, SynthesisResult)>, R2Error> {
+		synthesize(&self.fcn_config, &self.traces, backend)
+	}
+}
+
+// Trace one input and check the result, bad traces come back as R2Error::Rejected without the inputs
+fn checked_trace<T: Tracer>(tracer: &mut T, fcn: &FcnConfig, inputs: &[Location], outputs: &[Location], values: &[BaseT]) -> Result<Trace, R2Error> {
+	let trace = match tracer.trace(inputs, values, outputs) {
+		Ok(trace) => trace,
+		Err(err @ R2Error::Emulation { .. }) | Err(err @ R2Error::Exec(_)) => return Err(R2Error::Rejected(err.to_string())),
+		Err(err) => return Err(err),
+	};
+	if trace.stop == StopReason::MaxSteps {
+		return Err(R2Error::Rejected(format!("stop condition not reached in {} steps", fcn.max_steps)))
+	}
+	if let Some(n) = trace.probes.iter().position(Option::is_none) {
+		return Err(R2Error::Rejected(format!("probe {} not reached", fcn.probes[n].name())))
+	}
+	if fcn.check_determinism {
+		match tracer.trace(inputs, values, outputs) {
+			Ok(rerun) => if rerun.outputs != trace.outputs || rerun.probes != trace.probes || rerun.stop != trace.stop {
+				return Err(R2Error::Rejected("outputs differ on rerun".to_string()))
+			},
+			Err(err) => return Err(R2Error::Rejected(format!("rerun failed: {}", err))),
+		}
+	}
+	Ok(trace)
+}
+
+// Synthesise an expression for every output register and report them together.
+// Needs no tracer, so it also runs from a trace file.
+pub fn deobfuscate(fcn: &FcnConfig, traces: &Traces, backend: Synthesiser) -> Result<Vec<(String, SynthesisResult)>, R2Error> {
+	let results = synthesize(fcn, traces, backend)?;
+	for (output_reg, result) in results.iter() {
+		println!("{} = {}", output_reg, result);
+		// Near misses often only lack a constant or get the width wrong
+		if !result.exact && !result.candidates.is_empty() {
+			println!("{}", result.explain(4));
+		}
+	}
+	Ok(results)
+}
+
+// Best expression and its score for every output register and probe, in the order of fcn.output_names.
+// Flag outputs get boolean expressions over the sign extended inputs. Stubbed calls are inputs too.
+pub fn synthesize(fcn: &FcnConfig, traces: &Traces, backend: Synthesiser) -> Result<Vec<(String, SynthesisResult)>, R2Error> {
+	let inputs = &traces.inputs;
+	let mut registers = fcn.input_regs.clone();
+	registers.extend(traces.stubs.iter().cloned());
+	if registers.is_empty() {
+		return Err(R2Error::Synthesis("no inputs".to_string()))
+	}
+	if traces.stops.is_empty() {
+		return Err(R2Error::Synthesis("no traces".to_string()))
+	}
+	// Playouts follow the session seed unless the settings bring their own
+	let backend = match backend {
+		Synthesiser::Tree(mut settings) => {
+			settings.seed = settings.seed.or(fcn.seed);
+			Synthesiser::Tree(settings)
+		},
+	};
+	let signed_inputs: HashMap<String,Vec<BaseT>> = inputs.iter()
+		.map(|(reg, values)| (reg.clone(), values.iter().map(|value| sign_extend(*value, traces.width(reg))).collect()))
+		.collect();
+	let mut results = Vec::new();
+	for output_reg in fcn.output_names().iter() {
+		let outputs = if let Some(outputs) = traces.outputs.get(output_reg) { outputs }
+		else { return Err(R2Error::Synthesis(format!("no traces for output: {}", output_reg))) };
+		let result = match &backend {
+			Synthesiser::Tree(settings) if traces.flags.contains(output_reg) => {
+				let mut synthesis = synth_tree::Synthesis::boolean(&registers, settings);
+				synthesis.synthesize(&signed_inputs, outputs, 1)
+			},
+			Synthesiser::Tree(settings) => {
+				let mut synthesis = synth_tree::Synthesis::new(&registers, settings);
+				synthesis.synthesize(inputs, outputs, traces.width(output_reg))
 			},
+		};
+		results.push((output_reg.clone(), result));
+	}
+	Ok(results)
+}
+
+// Derive inputs and outputs of fcn.loc from r2's function info (afij, afvj, afcf) and the
+// calling convention of the detected ABI. Arguments without a register become stack slots at fcn entry.
+pub fn infer_io(fcn: FcnConfig) -> Result<FcnConfig, R2Error> {
+	let mut r2 = spawn_r2(&fcn)?;
+	let fcn = infer_io_with(&mut r2, fcn);
+	r2.close();
+	fcn
+}
+
+// Same as infer_io but on an already analysed r2 session
+pub fn infer_io_with(r2: &mut R2Pipe, mut fcn: FcnConfig) -> Result<FcnConfig, R2Error> {
+	let cmd = format!("afij @ {}", fcn.loc);
+	let info = r2_cmdj(r2, &cmd)?;
+	let calltype = if let Some(calltype) = info[0]["calltype"].as_str() { calltype.to_string() }
+	else { return Err(R2Error::Parse { cmd, output: info.to_string() }) };
+	let vars = r2_cmdj(r2, &format!("afvj @ {}", fcn.loc))?;
+	let nargs = ::std::cmp::max(info[0]["nargs"].as_u64().unwrap_or(0) as usize, count_args(&vars));
+
+	let mut query = |cmd: &str| -> Result<String, R2Error> {
+		Ok(r2_cmd(r2, cmd)?.trim().to_string())
+	};
+	let signature = query(&format!("afcf @ {}", fcn.loc))?;
+	let ptr_size = query("e asm.bits")?.parse::<usize>().unwrap_or(64) / 8;
+	let ret_on_stack = query("e asm.arch")?.starts_with("x86");
+	let sp = query("arn SP")?;
+	let mut arg_regs = Vec::new();
+	for n in 0..nargs {
+		arg_regs.push(query(&format!("k anal/cc/cc.{}.arg{}", calltype, n))?);
+	}
+	let ret = query(&format!("k anal/cc/cc.{}.ret", calltype))?;
+
+	fcn.input_regs = arg_locations(&arg_regs, &sp, ptr_size, ret_on_stack);
+	fcn.output_regs = if signature.starts_with("void ") || ret.is_empty() { Vec::new() } else { vec![ret] };
+	Ok(fcn)
+}
+
+// Register and stack arguments listed by afvj
+fn count_args(vars: &serde_json::Value) -> usize {
+	let mut n = 0;
+	for kind in ["reg", "sp", "bp"].iter() {
+		if let Some(vars) = vars[*kind].as_array() {
+			n += vars.iter().filter(|var| var["kind"] == "reg" || var["kind"] == "arg").count();
 		}
 	}
+	n
+}
+
+// Location descriptors for arguments, args without a register are consecutive stack slots
+fn arg_locations(arg_regs: &[String], sp: &str, ptr_size: usize, ret_on_stack: bool) -> Vec<String> {
+	let mut slot = if ret_on_stack { 1 } else { 0 };
+	arg_regs.iter().map(|reg| {
+		if reg.is_empty() || reg == "stack" {
+			slot += 1;
+			format!("[{}+{}]:{}", sp, (slot - 1) * ptr_size, ptr_size)
+		} else { reg.clone() }
+	}).collect()
 }
 
-// TODO: Add types enum to support multiple random input types
-fn get_random_input(n: u8) -> Vec<String> {
-	let mut result: Vec<String> = Vec::new();
-	for _i in 0..n {
-		loop {
-			let r = random::<u8>();
-			if r != 0 {
-				result.push(r.to_string());
-				break
+// Bytes of a hex string, whitespace is ignored
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, R2Error> {
+	let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+	let hex = hex.trim_start_matches("0x");
+	if !hex.len().is_multiple_of(2) {
+		return Err(R2Error::Config("odd number of hex digits".to_string()))
+	}
+	let mut bytes = Vec::new();
+	for n in (0..hex.len()).step_by(2) {
+		match u8::from_str_radix(&hex[n..n + 2], 16) {
+			Ok(byte) => bytes.push(byte),
+			Err(_) => return Err(R2Error::Config(format!("invalid hex at {}", n))),
+		}
+	}
+	Ok(bytes)
+}
+
+// Spawn r2 on the target, run the analysis and the init script
+pub fn spawn_r2(fcn: &FcnConfig) -> Result<R2Pipe, R2Error> {
+	let mut r2pipe = match &fcn.raw {
+		Some(raw) => load_raw(raw)?,
+		None => spawn(&fcn.path)?,
+	};
+
+	r2_cmd(&mut r2pipe, fcn.analysis.cmd())?;
+	if let Some(script) = &fcn.r2_script {
+		script.run(&mut r2pipe)?;
+	}
+	Ok(r2pipe)
+}
+
+// Map raw code at its base address and set up arch, bits, endianness and OS.
+// r2 starts without a file, so the buffer is only mapped once.
+fn load_raw(raw: &RawCode) -> Result<R2Pipe, R2Error> {
+	let uri = format!("malloc://{}", raw.bytes.len());
+	let mut r2pipe = spawn("--")?;
+	if let Some(os) = &raw.os {
+		r2_cmd(&mut r2pipe, &format!("e asm.os={}", os))?;
+	}
+	let cmd = format!("e asm.arch={};e asm.bits={};e cfg.bigendian={};o {} {};s {};wx {}",
+		raw.arch, raw.bits, raw.big_endian, uri, raw.base, raw.base, raw.to_hex());
+	r2_cmd(&mut r2pipe, &cmd)?;
+	Ok(r2pipe)
+}
+
+// Spawn r2 on a file or uri, nothing else
+pub fn spawn(path: &str) -> Result<R2Pipe, R2Error> {
+	R2Pipe::spawn(path, None).map_err(|e| R2Error::Spawn { path: path.to_string(), output: e.to_string() })
+}
+
+// r2pipe commands with the command attached to the error
+pub fn r2_cmd(r2: &mut R2Pipe, cmd: &str) -> Result<String, R2Error> {
+	r2.cmd(cmd).map_err(|e| R2Error::Cmd { cmd: cmd.to_string(), output: e })
+}
+
+pub fn r2_cmdj(r2: &mut R2Pipe, cmd: &str) -> Result<serde_json::Value, R2Error> {
+	r2.cmdj(cmd).map_err(|e| R2Error::Cmd { cmd: cmd.to_string(), output: e })
+}
+
+// Parse a hex number printed by r2, e.g. by ?v or pv
+pub fn parse_hex(cmd: &str, output: &str) -> Result<u64, R2Error> {
+	u64::from_str_radix(output.trim().trim_start_matches("0x"), 16)
+		.map_err(|_| R2Error::Parse { cmd: cmd.to_string(), output: output.to_string() })
+}
+
+// Resolve a flag or address with r2. ?v prints 0 for names r2 does not know,
+// so 0 is only taken if the location is a number or a known flag.
+pub fn resolve_addr(r2: &mut R2Pipe, loc: &str) -> Result<u64, R2Error> {
+	let cmd = "?v ".to_string() + loc;
+	let res = r2_cmd(r2, &cmd)?;
+	match parse_hex(&cmd, &res)? {
+		0 if !is_known_addr(r2, loc)? => Err(R2Error::Parse { cmd, output: res }),
+		addr => Ok(addr),
+	}
+}
+
+fn is_known_addr(r2: &mut R2Pipe, loc: &str) -> Result<bool, R2Error> {
+	let (name, _) = Location::split_addr(loc);
+	if is_number(name) {
+		return Ok(true)
+	}
+	let flags = r2_cmdj(r2, "fj")?;
+	Ok(flags.as_array().is_some_and(|flags| flags.iter().any(|flag| flag["name"] == name)))
+}
+
+fn is_number(name: &str) -> bool {
+	match name.strip_prefix("0x") {
+		Some(hex) => u64::from_str_radix(hex, 16).is_ok(),
+		None => name.parse::<u64>().is_ok(),
+	}
+}
+
+// Resolve the stop locations of a fcn with r2
+pub fn resolve_stop_addrs(r2: &mut R2Pipe, stop: &StopCondition) -> Result<Vec<u64>, R2Error> {
+	match stop {
+		StopCondition::Ret => Ok(Vec::new()),
+		StopCondition::Address(loc) => Ok(vec![resolve_addr(r2, loc)?]),
+		StopCondition::Addresses(locs) => {
+			let mut addrs = Vec::new();
+			for loc in locs.iter() {
+				addrs.push(resolve_addr(r2, loc)?);
 			}
+			Ok(addrs)
+		},
+	}
+}
+
+#[test]
+fn test_raw_code_hex() {
+	let raw = RawCode::from_hex("8d 04 37\nc3", "x86", 64, false, 0x1000).unwrap();
+	assert_eq!(raw.bytes, vec![0x8d, 0x04, 0x37, 0xc3]);
+	assert_eq!(raw.to_hex(), "8d0437c3");
+	assert!(RawCode::from_hex("8d0", "x86", 64, false, 0).is_err());
+	assert!(RawCode::from_hex("zz", "x86", 64, false, 0).is_err());
+}
+
+#[test]
+fn test_is_number() {
+	assert!(is_number("0") && is_number("0x0") && is_number("4096"));
+	assert!(!is_number("entry0") && !is_number("0xzz") && !is_number(""));
+}
+
+#[test]
+fn test_arg_locations() {
+	let regs: Vec<String> = vec!["rdi", "rsi", "", ""].iter().map(|x| x.to_string()).collect();
+	assert_eq!(arg_locations(&regs, "rsp", 8, true), vec!["rdi", "rsi", "[rsp+8]:8", "[rsp+16]:8"]);
+	let regs: Vec<String> = vec!["stack", "stack"].iter().map(|x| x.to_string()).collect();
+	assert_eq!(arg_locations(&regs, "esp", 4, true), vec!["[esp+4]:4", "[esp+8]:4"]);
+	assert_eq!(arg_locations(&regs, "sp", 4, false), vec!["[sp+0]:4", "[sp+4]:4"]);
+}
+
+#[test]
+fn test_trace_validation() {
+	struct FlakyTracer { runs: usize }
+	impl Tracer for FlakyTracer {
+		fn init(_fcn: &FcnConfig, _inputs: &[Location], _outputs: &[Location]) -> Result<FlakyTracer, R2Error> {
+			Ok(FlakyTracer { runs: 0 })
+		}
+		// Run 3 never stops, runs 4 and 5 disagree and run 6 faults
+		fn trace(&mut self, _inputs: &[Location], values: &[BaseT], _outputs: &[Location]) -> Result<Trace, R2Error> {
+			self.runs += 1;
+			match self.runs {
+				3 => Ok(Trace { outputs: vec![0], stop: StopReason::MaxSteps, stubs: Vec::new(), probes: Vec::new() }),
+				5 => Ok(Trace { outputs: vec![values[0] + 1], stop: StopReason::Ret, stubs: Vec::new(), probes: Vec::new() }),
+				6 => Err(R2Error::Emulation { cmd: "aes".to_string(), output: "trap".to_string() }),
+				_ => Ok(Trace { outputs: vec![values[0]], stop: StopReason::Ret, stubs: Vec::new(), probes: Vec::new() }),
+			}
+		}
+	}
+	let mut fcn = FcnConfig::new("calc", "sym.calc");
+	fcn.input_regs = vec!["edi".to_string()];
+	fcn.output_regs = vec!["rax".to_string()];
+	fcn.seed = Some(1);
+	let mut session = Session::<FlakyTracer>::init(fcn).unwrap();
+	assert!(session.add_trace().is_ok());
+	assert!(session.add_trace().is_err());
+	assert!(session.add_trace().is_err());
+	assert!(session.add_trace().is_err());
+	assert!(session.add_trace().is_ok());
+	assert_eq!(session.rejected().len(), 3);
+	assert!(session.rejected()[0].starts_with("stop condition not reached"));
+	assert!(session.rejected()[1].starts_with("outputs differ on rerun"));
+	assert!(session.rejected()[2].starts_with("emulation fault"));
+	assert_eq!(session.traces.stops.len(), 2);
+
+	let mut fcn = FcnConfig::new("calc", "sym.calc");
+	fcn.input_regs = vec!["rdi".to_string()];
+	fcn.output_regs = vec!["*rdi:4".to_string()];
+	assert!(Session::<FlakyTracer>::init(fcn).is_err());
+}
+
+#[test]
+fn test_trace_pool() {
+	struct DoubleTracer;
+	impl Tracer for DoubleTracer {
+		fn init(_fcn: &FcnConfig, _inputs: &[Location], _outputs: &[Location]) -> Result<DoubleTracer, R2Error> {
+			Ok(DoubleTracer)
+		}
+		fn trace(&mut self, _inputs: &[Location], values: &[BaseT], _outputs: &[Location]) -> Result<Trace, R2Error> {
+			Ok(Trace { outputs: vec![values[0].wrapping_mul(2)], stop: StopReason::Ret, stubs: Vec::new(), probes: Vec::new() })
 		}
 	}
-	result
+	let mut fcn = FcnConfig::new("calc", "sym.calc");
+	fcn.input_regs = vec!["edi".to_string()];
+	fcn.output_regs = vec!["rax".to_string()];
+	fcn.seed = Some(7);
+	let mut single = Session::<DoubleTracer>::init(fcn.clone()).unwrap();
+	fcn.n_tracers = 3;
+	let mut pool = Session::<DoubleTracer>::init(fcn).unwrap();
+	assert_eq!(pool.tracers.len(), 3);
+	single.add_traces(5);
+	single.add_trace().unwrap();
+	assert_eq!(pool.add_traces(6).len(), 6);
+	assert_eq!(pool.traces.inputs["edi"], single.traces.inputs["edi"]);
+	assert_eq!(pool.traces.outputs["rax"], single.traces.outputs["rax"]);
+	assert_eq!(pool.traces.outputs["rax"][5], pool.traces.inputs["edi"][5] * 2);
+}
+
+#[test]
+fn test_probes() {
+	struct ProbeTracer;
+	impl Tracer for ProbeTracer {
+		fn init(_fcn: &FcnConfig, _inputs: &[Location], _outputs: &[Location]) -> Result<ProbeTracer, R2Error> {
+			Ok(ProbeTracer)
+		}
+		// The second probe is only reached for odd inputs
+		fn trace(&mut self, _inputs: &[Location], values: &[BaseT], _outputs: &[Location]) -> Result<Trace, R2Error> {
+			let odd = if values[0] & 1 == 1 { Some(values[0]) } else { None };
+			Ok(Trace { outputs: vec![values[0]], stop: StopReason::Ret, stubs: Vec::new(), probes: vec![Some(values[0] + 1), odd] })
+		}
+	}
+	let mut fcn = FcnConfig::new("calc", "sym.calc");
+	fcn.input_regs = vec!["edi".to_string()];
+	fcn.output_regs = vec!["rax".to_string()];
+	fcn.probes = vec![Probe::new("0x1150", "ecx"), Probe::new("sym.calc+8", "edx")];
+	fcn.seed = Some(3);
+	assert_eq!(fcn.output_names(), vec!["rax", "ecx@0x1150", "edx@sym.calc+8"]);
+	let mut session = Session::<ProbeTracer>::init(fcn).unwrap();
+	session.add_traces(8);
+	assert!(session.rejected().iter().all(|reason| reason.starts_with("probe edx@sym.calc+8 not reached")));
+	assert_eq!(session.traces.stops.len() + session.rejected().len(), 8);
+	assert!(session.traces.inputs["edi"].iter().all(|value| value & 1 == 1));
+	let ecx: Vec<BaseT> = session.traces.inputs["edi"].iter().map(|value| value + 1).collect();
+	assert_eq!(session.traces.outputs["ecx@0x1150"], ecx);
+	assert_eq!(session.traces.outputs["edx@sym.calc+8"], session.traces.inputs["edi"]);
+}
+
+#[test]
+fn test_push_stubs() {
+	let mut fcn = FcnConfig::new("calc", "sym.calc");
+	fcn.input_regs = vec!["edi".to_string()];
+	let mut traces = Traces { inputs: HashMap::new(), outputs: HashMap::new(), stops: Vec::new(), widths: HashMap::new(), flags: HashSet::new(), stubs: Vec::new() };
+	traces.inputs.insert("edi".to_string(), Vec::new());
+	traces.push(&fcn.input_regs, &[1], &[], &[], StopReason::Ret).unwrap();
+	traces.push_stubs(&[]);
+	traces.push(&fcn.input_regs, &[2], &[], &[], StopReason::Ret).unwrap();
+	traces.push_stubs(&[("rand@0x1150".to_string(), 5), ("rand@0x1150".to_string(), 7)]);
+	traces.push(&fcn.input_regs, &[3], &[], &[], StopReason::Ret).unwrap();
+	traces.push_stubs(&[("strlen@0x1160".to_string(), 3)]);
+	assert_eq!(traces.stubs, vec!["rand@0x1150", "strlen@0x1160"]);
+	assert_eq!(traces.inputs["rand@0x1150"], vec![0, 7, 0]);
+	assert_eq!(traces.inputs["strlen@0x1160"], vec![0, 0, 3]);
 }
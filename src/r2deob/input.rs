@@ -0,0 +1,125 @@
+extern crate rand;
+use rand::Rng;
+
+extern crate serde;
+use serde::{
+	Deserialize,
+	Serialize,
+};
+
+use std::collections::HashMap;
+
+use super::BaseT;
+
+// How input values for a trace are chosen. Values are generated per input
+// with the width of that input and stored zero extended.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum InputStrategy {
+	// Non-zero bytes
+	#[default]
+	Byte,
+	// Uniform random over the full width
+	Uniform,
+	// 0, 1, -1, MIN, MAX and powers of two
+	Boundary,
+	// Inclusive (min, max) per input descriptor, inputs without a range are uniform
+	Ranges(HashMap<String,(BaseT,BaseT)>),
+	// One of the strategies, picked at random for every trace
+	Mixed(Vec<InputStrategy>),
+}
+
+impl InputStrategy {
+	pub fn generate<R: Rng>(&self, rng: &mut R, names: &[String], widths: &[usize]) -> Vec<BaseT> {
+		if let InputStrategy::Mixed(strategies) = self {
+			return match rng.choose(strategies) {
+				Some(strategy) => strategy.generate(rng, names, widths),
+				None => InputStrategy::Uniform.generate(rng, names, widths),
+			}
+		}
+		names.iter().zip(widths)
+			.map(|(name, width)| self.value(rng, name, *width) & mask(*width) as BaseT)
+			.collect()
+	}
+
+	fn value<R: Rng>(&self, rng: &mut R, name: &str, width: usize) -> BaseT {
+		match self {
+			InputStrategy::Byte => rng.gen_range(1u16, 256u16) as BaseT,
+			InputStrategy::Boundary => *rng.choose(&boundary_values(width)).unwrap(),
+			InputStrategy::Ranges(ranges) => {
+				match ranges.get(name) {
+					Some((min, max)) => random_in_range(rng, *min, *max),
+					None => rng.gen::<BaseT>(),
+				}
+			},
+			_ => rng.gen::<BaseT>(),
+		}
+	}
+}
+
+pub fn mask(width: usize) -> u64 {
+	if width >= 8 { !0 } else { (1u64 << (width * 8)) - 1 }
+}
+
+// Sign extend a value stored zero extended with width bytes
+pub fn sign_extend(value: BaseT, width: usize) -> BaseT {
+	if width >= 8 { return value }
+	let shift = 64 - width as u32 * 8;
+	(value << shift) >> shift
+}
+
+fn boundary_values(width: usize) -> Vec<BaseT> {
+	if width == 0 { return vec![0] }
+	let bits = width as u32 * 8;
+	let min = 1u64 << (bits - 1);
+	let mut values = vec![0, 1, !0, min, min.wrapping_sub(1)];
+	for n in 1..bits - 1 {
+		values.push(1u64 << n);
+	}
+	values.iter().map(|x| *x as BaseT).collect()
+}
+
+fn random_in_range<R: Rng>(rng: &mut R, min: BaseT, max: BaseT) -> BaseT {
+	let span = (max as u64).wrapping_sub(min as u64);
+	if max < min || span == !0 {
+		return rng.gen::<BaseT>()
+	}
+	(min as u64).wrapping_add(rng.gen::<u64>() % (span + 1)) as BaseT
+}
+
+#[test]
+fn test_input_boundary() {
+	let values = boundary_values(1);
+	assert_eq!(values.len(), 11);
+	assert!(values.contains(&0x80) && values.contains(&0x7f) && values.contains(&0x40));
+	let values = boundary_values(8);
+	assert!(values.contains(&BaseT::min_value()) && values.contains(&BaseT::max_value()) && values.contains(&-1));
+	assert_eq!(boundary_values(0), vec![0]);
+}
+
+#[test]
+fn test_input_generate() {
+	let mut rng = rand::thread_rng();
+	let names = vec!["esi".to_string(), "[rsp+8]:2".to_string()];
+	let mut ranges = HashMap::new();
+	ranges.insert("esi".to_string(), (-4, 4));
+	for _ in 0..64 {
+		let values = InputStrategy::Ranges(ranges.clone()).generate(&mut rng, &names, &[8, 2]);
+		assert!(values[0] >= -4 && values[0] <= 4);
+		assert!(values[1] >= 0 && values[1] <= 0xffff);
+		let values = InputStrategy::Mixed(vec![InputStrategy::Uniform, InputStrategy::Boundary]).generate(&mut rng, &names, &[4, 1]);
+		assert!(values[0] >= 0 && values[0] <= 0xffff_ffff && values[1] <= 0xff);
+		let values = InputStrategy::Byte.generate(&mut rng, &names, &[8, 8]);
+		assert!(values[0] > 0 && values[0] < 256);
+	}
+}
+
+#[test]
+fn test_input_seeded() {
+	use super::seeded_rng;
+	let names = vec!["rdi".to_string(), "rsi".to_string()];
+	let strategy = InputStrategy::Mixed(vec![InputStrategy::Uniform, InputStrategy::Boundary]);
+	let (mut a, mut b) = (seeded_rng(42), seeded_rng(42));
+	for _ in 0..16 {
+		assert_eq!(strategy.generate(&mut a, &names, &[8, 4]), strategy.generate(&mut b, &names, &[8, 4]));
+	}
+}
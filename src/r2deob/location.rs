@@ -0,0 +1,91 @@
+// Descriptors for the places a target reads its inputs from and writes its outputs to.
+//   rax            register
+//   [rsp+8]:4      4 bytes at an address expression, registers are taken at fcn entry
+//   obj.g_key:8    8 bytes at a flag or address
+//   *rdi:8         8 bytes in a scratch buffer that rdi points to at fcn entry (outputs only)
+use super::R2Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+	Register(String),
+	Memory(String, usize),
+	Pointer(String, usize),
+}
+
+impl Location {
+	pub fn parse(desc: &str) -> Result<Location, R2Error> {
+		let desc = desc.trim();
+		let (addr, size) = match desc.rfind(':') {
+			Some(pos) => (&desc[..pos], &desc[pos + 1..]),
+			None => return Ok(Location::Register(desc.to_string())),
+		};
+		let size: usize = if let Ok(size) = size.parse() { size }
+		else { return Err(R2Error::Config(format!("invalid size in location: {}", desc))) };
+		if ![1, 2, 4, 8].contains(&size) {
+			return Err(R2Error::Config(format!("unsupported size in location: {}", desc)))
+		}
+		let addr = if addr.starts_with('[') && addr.ends_with(']') { &addr[1..addr.len() - 1] }
+		else { addr };
+		if addr.is_empty() || addr == "*" {
+			return Err(R2Error::Config(format!("missing address in location: {}", desc)))
+		}
+		if let Some(reg) = addr.strip_prefix('*') {
+			return Ok(Location::Pointer(reg.to_string(), size))
+		}
+		Ok(Location::Memory(addr.to_string(), size))
+	}
+
+	// Width in bytes, registers are assumed to be full width, see Tracer::width
+	pub fn width(&self) -> usize {
+		match self {
+			Location::Register(_) => 8,
+			Location::Memory(_, size) | Location::Pointer(_, size) => *size,
+		}
+	}
+
+	// Split an address expression like "rsp+8" into its base and offset
+	pub fn split_addr(addr: &str) -> (&str, i64) {
+		if let Some(pos) = addr.rfind(['+', '-']) {
+			let offset = addr[pos + 1..].trim();
+			let offset = match offset.strip_prefix("0x") {
+				Some(hex) => i64::from_str_radix(hex, 16),
+				None => offset.parse(),
+			};
+			if let Ok(offset) = offset {
+				let base = addr[..pos].trim();
+				if &addr[pos..pos + 1] == "-" { return (base, -offset) }
+				return (base, offset)
+			}
+		}
+		(addr.trim(), 0)
+	}
+}
+
+impl ::std::fmt::Display for Location {
+	fn fmt(&self, w: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+		match self {
+			Location::Register(x) => write!(w, "{}", x),
+			Location::Memory(addr, size) => write!(w, "[{}]:{}", addr, size),
+			Location::Pointer(reg, size) => write!(w, "*{}:{}", reg, size),
+		}
+	}
+}
+
+#[test]
+fn test_location_parse() {
+	assert_eq!(Location::parse("esi").unwrap(), Location::Register("esi".to_string()));
+	assert_eq!(Location::parse("[rsp+8]:4").unwrap(), Location::Memory("rsp+8".to_string(), 4));
+	assert_eq!(Location::parse("obj.g_key:8").unwrap(), Location::Memory("obj.g_key".to_string(), 8));
+	assert_eq!(Location::parse("*rdi:4").unwrap(), Location::Pointer("rdi".to_string(), 4));
+	assert!(Location::parse("[rsp+8]:3").is_err());
+	assert!(Location::parse("*:4").is_err());
+	assert!(Location::parse("[]:4").is_err());
+}
+
+#[test]
+fn test_location_split_addr() {
+	assert_eq!(Location::split_addr("rsp+8"), ("rsp", 8));
+	assert_eq!(Location::split_addr("rbp - 0x10"), ("rbp", -16));
+	assert_eq!(Location::split_addr("obj.g_key"), ("obj.g_key", 0));
+	assert_eq!(Location::split_addr("0x601040"), ("0x601040", 0));
+}
@@ -5,10 +5,82 @@ pub mod sat_interface;
 pub mod ast;
 pub mod score;
 pub mod calc;
+pub mod location;
+pub mod reg_profile;
+pub mod snapshot;
+pub mod input;
+pub mod trace_esil;
+pub mod trace_file;
+pub mod batch;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod trace_ptrace;
+
+extern crate rand;
+use rand::{
+	rngs::StdRng,
+	SeedableRng,
+};
+
+use std::fmt;
 
 pub type BaseT = i64;
 
+// Everything that can go wrong between spawning r2 and reporting an expression.
+// Variants coming from r2 carry the command and what r2 answered.
+#[derive(Debug)]
 pub enum R2Error {
-	PipeFail,
-	CmdFail,
+	// r2 could not be started on the target
+	Spawn { path: String, output: String },
+	// An r2 command failed
+	Cmd { cmd: String, output: String },
+	UnknownRegister(String),
+	// Invalid FcnConfig, e.g. a malformed location descriptor
+	Config(String),
+	// Emulation did not get through the target
+	Emulation { cmd: String, output: String },
+	// Native execution of the target failed
+	Exec(String),
+	// r2 answered with something that is not the expected value
+	Parse { cmd: String, output: String },
+	Synthesis(String),
+	// add_trace threw the trace away, see Session::rejected
+	Rejected(String),
+	// Reading or writing trace files
+	Io(String),
+}
+
+impl fmt::Display for R2Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			R2Error::Spawn { path, output } => write!(f, "could not spawn r2 on {}: {}", path, output),
+			R2Error::Cmd { cmd, output } => write!(f, "r2 command failed: {}: {}", cmd, output),
+			R2Error::UnknownRegister(reg) => write!(f, "unknown register: {}", reg),
+			R2Error::Config(msg) => write!(f, "invalid config: {}", msg),
+			R2Error::Emulation { cmd, output } => write!(f, "emulation fault at {}: {}", cmd, output),
+			R2Error::Exec(msg) => write!(f, "execution failed: {}", msg),
+			R2Error::Parse { cmd, output } => write!(f, "could not parse output of {}: {}", cmd, output.trim()),
+			R2Error::Synthesis(msg) => write!(f, "synthesis failed: {}", msg),
+			R2Error::Rejected(reason) => write!(f, "trace rejected: {}", reason),
+			R2Error::Io(msg) => write!(f, "{}", msg),
+		}
+	}
+}
+
+// Expand a u64 seed with splitmix64, so equal seeds give equal runs
+pub fn seeded_rng(seed: u64) -> StdRng {
+	let mut state = seed;
+	let mut bytes = [0u8; 32];
+	for chunk in bytes.chunks_mut(8) {
+		chunk.copy_from_slice(&splitmix(state).to_le_bytes());
+		state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+	}
+	StdRng::from_seed(bytes)
+}
+
+// One step of splitmix64, also the PRNG of the modelled libc rand
+pub(crate) fn splitmix(state: u64) -> u64 {
+	let mut z = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+	z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+	z ^ (z >> 31)
 }
@@ -0,0 +1,114 @@
+// r2's register profile (drpj), used to find the width of a register and the full
+// register that holds a sub-register like eax, ax, al or ah
+extern crate r2pipe;
+use r2pipe::R2Pipe;
+
+use std::collections::HashMap;
+
+use super::{
+	engine::r2_cmdj,
+	R2Error,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Register {
+	pub name: String,
+	// gpr, flg, fpu, ...
+	pub kind: String,
+	// Size and offset into the register file, both in bits
+	pub size: usize,
+	pub offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegProfile {
+	regs: Vec<Register>,
+	// Role aliases like PC, SP or A0
+	aliases: HashMap<String,String>,
+}
+
+impl RegProfile {
+	pub fn load(r2: &mut R2Pipe) -> Result<RegProfile, R2Error> {
+		let cmd = "drpj";
+		let json = r2_cmdj(r2, cmd)?;
+		RegProfile::parse(&json).ok_or_else(|| R2Error::Parse { cmd: cmd.to_string(), output: json.to_string() })
+	}
+
+	pub fn parse(json: &serde_json::Value) -> Option<RegProfile> {
+		let mut regs = Vec::new();
+		for reg in json["reg_info"].as_array()?.iter() {
+			regs.push(Register {
+				name: reg["name"].as_str()?.to_string(),
+				kind: reg["type_str"].as_str().unwrap_or("gpr").to_string(),
+				size: reg["size"].as_u64()? as usize,
+				offset: reg["offset"].as_u64()? as usize,
+			});
+		}
+		let mut aliases = HashMap::new();
+		if let Some(alias_info) = json["alias_info"].as_array() {
+			for alias in alias_info.iter() {
+				if let (Some(role), Some(reg)) = (alias["role_str"].as_str(), alias["reg"].as_str()) {
+					aliases.insert(role.to_string(), reg.to_string());
+				}
+			}
+		}
+		Some(RegProfile { regs, aliases })
+	}
+
+	// Register by name or role alias
+	pub fn get(&self, name: &str) -> Option<&Register> {
+		let name = self.aliases.get(name).map_or(name, |reg| reg.as_str());
+		self.regs.iter().find(|reg| reg.name == name)
+	}
+
+	// Widest register of the same kind that contains name, e.g. rax for eax and ah
+	pub fn parent(&self, name: &str) -> Option<&Register> {
+		let reg = self.get(name)?;
+		self.regs.iter()
+			.filter(|other| other.kind == reg.kind && other.offset <= reg.offset && other.offset + other.size >= reg.offset + reg.size)
+			.max_by_key(|other| other.size)
+	}
+
+	// Width in bytes, flags round up to a byte
+	pub fn width(&self, name: &str) -> Option<usize> {
+		self.get(name).map(|reg| reg.size.div_ceil(8))
+	}
+
+	// Value of name taken from the value of its parent register
+	pub fn extract(&self, name: &str, parent_value: u64) -> Option<u64> {
+		let reg = self.get(name)?;
+		let parent = self.parent(name)?;
+		let shift = reg.offset - parent.offset;
+		let value = if shift >= 64 { 0 } else { parent_value >> shift };
+		Some(if reg.size >= 64 { value } else { value & ((1u64 << reg.size) - 1) })
+	}
+}
+
+#[test]
+fn test_reg_profile() {
+	let json = serde_json::from_str(r#"{
+		"alias_info": [{"role": 0, "role_str": "PC", "reg": "rip"}, {"role": 5, "role_str": "A0", "reg": "rdi"}],
+		"reg_info": [
+			{"type": 0, "type_str": "gpr", "name": "rax", "size": 64, "offset": 640},
+			{"type": 0, "type_str": "gpr", "name": "eax", "size": 32, "offset": 640},
+			{"type": 0, "type_str": "gpr", "name": "ax", "size": 16, "offset": 640},
+			{"type": 0, "type_str": "gpr", "name": "al", "size": 8, "offset": 640},
+			{"type": 0, "type_str": "gpr", "name": "ah", "size": 8, "offset": 648},
+			{"type": 0, "type_str": "gpr", "name": "rdi", "size": 64, "offset": 896},
+			{"type": 0, "type_str": "gpr", "name": "rip", "size": 64, "offset": 1024},
+			{"type": 1, "type_str": "flg", "name": "eflags", "size": 32, "offset": 1152},
+			{"type": 1, "type_str": "flg", "name": "zf", "size": 1, "offset": 1158}
+		]
+	}"#).unwrap();
+	let profile = RegProfile::parse(&json).unwrap();
+	assert_eq!(profile.width("eax"), Some(4));
+	assert_eq!(profile.width("zf"), Some(1));
+	assert_eq!(profile.width("xmm0"), None);
+	assert_eq!(profile.get("A0").unwrap().name, "rdi");
+	assert_eq!(profile.parent("ah").unwrap().name, "rax");
+	assert_eq!(profile.parent("zf").unwrap().name, "eflags");
+	assert_eq!(profile.extract("eax", 0x1122_3344_5566_7788), Some(0x5566_7788));
+	assert_eq!(profile.extract("ah", 0x1122_3344_5566_7788), Some(0x77));
+	assert_eq!(profile.extract("zf", 0x40), Some(1));
+	assert_eq!(profile.extract("rax", 0x1122_3344_5566_7788), Some(0x1122_3344_5566_7788));
+}
@@ -1,5 +1,10 @@
-use std::cmp;
-use crate::r2deob::BaseT;
+use crate::r2deob::{
+	input::{
+		mask,
+		sign_extend,
+	},
+	BaseT,
+};
 
 #[derive(Debug, PartialEq)]
 pub enum Score {
@@ -15,50 +20,63 @@ impl Default for Score {
 	fn default() -> Self { Score::Unknown }
 }
 
+// Values are compared on the width of the output in bytes, bits above it are ignored
 impl Score {
-	fn hamming_distance(result_test: BaseT, result_true: BaseT) -> Score {
-		Score::HammingDistance(1.0 - (result_test ^ result_true).count_ones() as f32 / 64.0)
+	fn hamming_distance(result_test: BaseT, result_true: BaseT, width: usize) -> Score {
+		let diff = (result_test ^ result_true) as u64 & mask(width);
+		Score::HammingDistance(1.0 - diff.count_ones() as f32 / (width * 8) as f32)
 	}
 
-	fn abs_distance(result_test: BaseT, result_true: BaseT) -> Score {
-		Score::AbsDistance((cmp::min(result_test, result_true) as f64 / cmp::max(result_test, result_true) as f64) as f32)
+	fn abs_distance(result_test: BaseT, result_true: BaseT, width: usize) -> Score {
+		let (result_test, result_true) = (sign_extend(result_test, width), sign_extend(result_true, width));
+		if result_test == result_true {
+			return Score::AbsDistance(1.0)
+		}
+		// Zero or different signs would give NaN or negative ratios
+		if result_test.signum() * result_true.signum() <= 0 {
+			return Score::AbsDistance(0.0)
+		}
+		let (a, b) = ((result_test as f64).abs(), (result_true as f64).abs());
+		Score::AbsDistance((a.min(b) / a.max(b)) as f32)
 	}
 
-	fn range_distance(result_test: BaseT, result_true: BaseT) -> Score {
+	fn range_distance(result_test: BaseT, result_true: BaseT, width: usize) -> Score {
 		let bytes_test = result_test.to_le_bytes();
 		let bytes_true = result_true.to_le_bytes();
+		let width = width.min(bytes_test.len());
 		let mut result = 0;
-		for i in 0..bytes_test.len() {
+		for i in 0..width {
 			if bytes_test[i] == bytes_true[i] {
 				result += 1;
 			}
 		}
-		Score::RangeDistance(result as f32 / bytes_test.len() as f32)
+		Score::RangeDistance(result as f32 / width as f32)
 	}
 
-	fn combined(result_test: BaseT, result_true: BaseT) -> Score {
+	fn combined(result_test: BaseT, result_true: BaseT, width: usize) -> Score {
 		let mut result: f32 = 0.0;
 		let mut scores: f32 = 0.0;
-		if let Score::HammingDistance(x) = Score::hamming_distance(result_test, result_true) {
+		if let Score::HammingDistance(x) = Score::hamming_distance(result_test, result_true, width) {
 			result += x;
 			scores += 1.0;
 		}
-		if let Score::AbsDistance(x) = Score::abs_distance(result_test, result_true) {
+		if let Score::AbsDistance(x) = Score::abs_distance(result_test, result_true, width) {
 			result += x;
 			scores += 1.0;
 		}
-		if let Score::RangeDistance(x) = Score::range_distance(result_test, result_true) {
+		if let Score::RangeDistance(x) = Score::range_distance(result_test, result_true, width) {
 			result += x;
 			scores += 1.0;
 		}
 		Score::Combined(result / scores)
 	}
 
-	pub fn get(result_test: &Vec<BaseT>, result_true: &Vec<BaseT>) -> Score {
+	pub fn get(result_test: &[BaseT], result_true: &[BaseT], width: usize) -> Score {
+		let width = width.clamp(1, 8);
 		let mut result: f32 = 0.0;
 		let mut scores: f32 = 0.0;
 		for (r_test, r_true) in result_test.iter().zip(result_true) {
-			if let Score::Combined(x) = Score::combined(*r_test, *r_true) {
+			if let Score::Combined(x) = Score::combined(*r_test, *r_true, width) {
 				result += x;
 				scores += 1.0;
 			}
@@ -69,12 +87,26 @@ impl Score {
 
 #[test]
 fn score_test() {
-	assert_eq!(Score::HammingDistance(0.96875), Score::hamming_distance(3, 5));
-	assert_eq!(Score::AbsDistance(0.6), Score::abs_distance(3, 5));
-	assert_eq!(Score::RangeDistance(0.875), Score::range_distance(3, 5));
-	assert_eq!(Score::Combined(0.8145833), Score::combined(3, 5));
-	assert_eq!(Score::Combined(1.0), Score::get(&vec![1,2,3,4,5,6,7,8], &vec![1,2,3,4,5,6,7,8]));
-	assert_eq!(Score::RangeDistance(1.0), Score::range_distance(3, 3));
-	assert_eq!(Score::HammingDistance(1.0), Score::hamming_distance(3, 3));
-	assert_eq!(Score::AbsDistance(1.0), Score::abs_distance(3, 3));
+	assert_eq!(Score::HammingDistance(0.96875), Score::hamming_distance(3, 5, 8));
+	assert_eq!(Score::AbsDistance(0.6), Score::abs_distance(3, 5, 8));
+	assert_eq!(Score::RangeDistance(0.875), Score::range_distance(3, 5, 8));
+	assert_eq!(Score::Combined(0.8145833), Score::combined(3, 5, 8));
+	assert_eq!(Score::Combined(1.0), Score::get(&vec![1,2,3,4,5,6,7,8], &vec![1,2,3,4,5,6,7,8], 8));
+	assert_eq!(Score::RangeDistance(1.0), Score::range_distance(3, 3, 8));
+	assert_eq!(Score::HammingDistance(1.0), Score::hamming_distance(3, 3, 8));
+	assert_eq!(Score::AbsDistance(1.0), Score::abs_distance(3, 3, 8));
+	assert_eq!(Score::AbsDistance(1.0), Score::abs_distance(0, 0, 8));
+	assert_eq!(Score::AbsDistance(0.0), Score::abs_distance(-3, 5, 8));
+	assert_eq!(Score::AbsDistance(0.6), Score::abs_distance(-3, -5, 8));
+	assert_eq!(Score::Combined(1.0), Score::get(&vec![0,-1,BaseT::min_value()], &vec![0,-1,BaseT::min_value()], 8));
+}
+
+#[test]
+fn score_test_width() {
+	// Upper half garbage is ignored on 32 bit outputs
+	assert_eq!(Score::Combined(1.0), Score::get(&vec![0x1_0000_0005, -1], &vec![5, 0xffff_ffff], 4));
+	assert_eq!(Score::HammingDistance(0.9375), Score::hamming_distance(3, 5, 4));
+	assert_eq!(Score::RangeDistance(0.75), Score::range_distance(3, 5, 4));
+	assert_eq!(Score::AbsDistance(0.6), Score::abs_distance(0xffff_fffd, 0xffff_fffb, 4));
+	assert!(Score::get(&vec![0x1_0000_0005], &vec![5], 8) != Score::Combined(1.0));
 }
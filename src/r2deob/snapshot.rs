@@ -0,0 +1,219 @@
+// Process state the target is emulated from instead of the zeroed memory of aeim:
+// an ELF core dump, a raw dump with a map file or a JSON description
+extern crate serde;
+use serde::{
+	Deserialize,
+	Serialize,
+};
+
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs;
+
+use super::{
+	engine::decode_hex,
+	R2Error,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Snapshot {
+	// x86-64 ELF core, memory from PT_LOAD and registers from the first NT_PRSTATUS
+	Core(String),
+	// Raw memory dump, each line of the map file is "start-end offset" in hex
+	Dump { path: String, map: String },
+	// {"registers": {"rsp": 1234}, "memory": [{"addr": 4096, "hex": "00ff"}]}
+	Json(String),
+}
+
+// Memory regions and register values of a loaded snapshot
+#[derive(Debug, Default)]
+pub struct SnapshotData {
+	pub regions: Vec<(u64, Vec<u8>)>,
+	pub registers: Vec<(String, u64)>,
+}
+
+#[derive(Deserialize)]
+struct JsonSnapshot {
+	#[serde(default)]
+	registers: HashMap<String, u64>,
+	#[serde(default)]
+	memory: Vec<JsonRegion>,
+}
+
+#[derive(Deserialize)]
+struct JsonRegion {
+	addr: u64,
+	hex: String,
+}
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+// Offset of pr_reg in struct elf_prstatus on x86-64
+const PRSTATUS_REGS: usize = 112;
+// struct user_regs_struct
+const USER_REGS: [&str; 27] = [
+	"r15", "r14", "r13", "r12", "rbp", "rbx", "r11", "r10", "r9", "r8", "rax", "rcx", "rdx", "rsi", "rdi",
+	"orig_rax", "rip", "cs", "rflags", "rsp", "ss", "fs_base", "gs_base", "ds", "es", "fs", "gs",
+];
+
+impl Snapshot {
+	pub fn load(&self) -> Result<SnapshotData, R2Error> {
+		match self {
+			Snapshot::Core(path) => parse_core(&read(path)?).map_err(|e| R2Error::Io(format!("{}: {}", path, e))),
+			Snapshot::Dump { path, map } => {
+				let dump = read(path)?;
+				let map_file = fs::read_to_string(map).map_err(|e| R2Error::Io(format!("could not read {}: {}", map, e)))?;
+				parse_dump(&dump, &map_file).map_err(|e| R2Error::Io(format!("{}: {}", map, e)))
+			},
+			Snapshot::Json(path) => {
+				let json = fs::read_to_string(path).map_err(|e| R2Error::Io(format!("could not read {}: {}", path, e)))?;
+				parse_json(&json).map_err(|e| R2Error::Io(format!("{}: {}", path, e)))
+			},
+		}
+	}
+}
+
+fn read(path: &str) -> Result<Vec<u8>, R2Error> {
+	fs::read(path).map_err(|e| R2Error::Io(format!("could not read {}: {}", path, e)))
+}
+
+fn parse_json(json: &str) -> Result<SnapshotData, String> {
+	let snapshot: JsonSnapshot = serde_json::from_str(json).map_err(|e| e.to_string())?;
+	let mut regions = Vec::new();
+	for region in snapshot.memory.iter() {
+		regions.push((region.addr, decode_hex(&region.hex).map_err(|e| e.to_string())?));
+	}
+	let mut registers: Vec<(String, u64)> = snapshot.registers.into_iter().collect();
+	registers.sort();
+	Ok(SnapshotData { regions, registers })
+}
+
+fn parse_dump(dump: &[u8], map: &str) -> Result<SnapshotData, String> {
+	let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|_| format!("invalid hex: {}", s));
+	let mut regions = Vec::new();
+	for line in map.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		let range: Vec<&str> = fields[0].split('-').collect();
+		if fields.len() != 2 || range.len() != 2 {
+			return Err(format!("expected start-end offset: {}", line))
+		}
+		let (start, end, offset) = (hex(range[0])?, hex(range[1])?, hex(fields[1])? as usize);
+		let size = end.checked_sub(start).ok_or_else(|| format!("end before start: {}", line))? as usize;
+		match offset.checked_add(size).and_then(|end| dump.get(offset..end)) {
+			Some(bytes) => regions.push((start, bytes.to_vec())),
+			None => return Err(format!("region outside of the dump: {}", line)),
+		}
+	}
+	Ok(SnapshotData { regions, registers: Vec::new() })
+}
+
+fn parse_core(core: &[u8]) -> Result<SnapshotData, String> {
+	if core.get(0..4) != Some(b"\x7fELF") {
+		return Err("not an ELF file".to_string())
+	}
+	if core.get(4) != Some(&2) || core.get(5) != Some(&1) || u16_at(core, 16)? != 4 || u16_at(core, 18)? != 62 {
+		return Err("only x86-64 little endian core dumps are supported".to_string())
+	}
+	let (phoff, phentsize, phnum) = (u64_at(core, 32)? as usize, u16_at(core, 54)? as usize, u16_at(core, 56)? as usize);
+	let mut data = SnapshotData::default();
+	for n in 0..phnum {
+		let ph = n.checked_mul(phentsize).and_then(|off| off.checked_add(phoff)).filter(|ph| *ph < core.len()).ok_or("segment outside of the file")?;
+		let (offset, filesz) = (u64_at(core, ph + 8)? as usize, u64_at(core, ph + 32)? as usize);
+		let segment = offset.checked_add(filesz).and_then(|end| core.get(offset..end)).ok_or("segment outside of the file")?;
+		match u32_at(core, ph)? {
+			// Segments that were not readable have no file content
+			PT_LOAD if filesz > 0 => data.regions.push((u64_at(core, ph + 16)?, segment.to_vec())),
+			PT_NOTE if data.registers.is_empty() => data.registers = prstatus_regs(segment)?,
+			_ => {},
+		}
+	}
+	Ok(data)
+}
+
+// Registers of the first NT_PRSTATUS note, i.e. the thread that dumped
+fn prstatus_regs(notes: &[u8]) -> Result<Vec<(String, u64)>, String> {
+	let align = |n: usize| (n + 3) & !3;
+	let mut pos = 0;
+	while pos + 12 <= notes.len() {
+		let (namesz, descsz, kind) = (u32_at(notes, pos)? as usize, u32_at(notes, pos + 4)? as usize, u32_at(notes, pos + 8)?);
+		let desc = pos + 12 + align(namesz);
+		if kind == NT_PRSTATUS {
+			let mut regs = Vec::new();
+			for (n, reg) in USER_REGS.iter().enumerate() {
+				regs.push((reg.to_string(), u64_at(notes, desc + PRSTATUS_REGS + n * 8)?));
+			}
+			return Ok(regs)
+		}
+		pos = desc + align(descsz);
+	}
+	Ok(Vec::new())
+}
+
+fn u16_at(bytes: &[u8], pos: usize) -> Result<u16, String> {
+	Ok(u64_le(bytes.get(pos..pos.checked_add(2).ok_or("truncated")?).ok_or("truncated")?) as u16)
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> Result<u32, String> {
+	Ok(u64_le(bytes.get(pos..pos.checked_add(4).ok_or("truncated")?).ok_or("truncated")?) as u32)
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> Result<u64, String> {
+	Ok(u64_le(bytes.get(pos..pos.checked_add(8).ok_or("truncated")?).ok_or("truncated")?))
+}
+
+fn u64_le(bytes: &[u8]) -> u64 {
+	bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+#[test]
+fn test_parse_snapshots() {
+	let data = parse_json(r#"{"registers": {"rsp": 4096, "rbx": 1}, "memory": [{"addr": 8192, "hex": "01 02 03"}]}"#).unwrap();
+	assert_eq!(data.registers, vec![("rbx".to_string(), 1), ("rsp".to_string(), 4096)]);
+	assert_eq!(data.regions, vec![(8192, vec![1, 2, 3])]);
+
+	let dump: Vec<u8> = (0..16).collect();
+	let data = parse_dump(&dump, "# heap\n0x1000-0x1004 8\n2000-2002 0\n").unwrap();
+	assert_eq!(data.regions, vec![(0x1000, vec![8, 9, 10, 11]), (0x2000, vec![0, 1])]);
+	assert!(parse_dump(&dump, "1000-1010 8").is_err());
+	assert!(parse_dump(&dump, "1000-1010 ffffffffffffffff").is_err());
+
+	// Header, one PT_NOTE and one PT_LOAD
+	let mut core = vec![0u8; 64 + 2 * 56];
+	core[0..6].copy_from_slice(b"\x7fELF\x02\x01");
+	core[16] = 4;
+	core[18] = 62;
+	core[32] = 64;
+	core[54] = 56;
+	core[56] = 2;
+	let mut note = vec![5, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+	note.extend_from_slice(b"CORE\0\0\0\0");
+	let mut desc = vec![0u8; PRSTATUS_REGS + USER_REGS.len() * 8];
+	desc[PRSTATUS_REGS + 10 * 8] = 0x2a;
+	note[4..8].copy_from_slice(&(desc.len() as u32).to_le_bytes());
+	note.extend_from_slice(&desc);
+	let note_off = core.len();
+	core.extend_from_slice(&note);
+	let load_off = core.len();
+	core.extend_from_slice(&[0xaa, 0xbb]);
+	for (n, (kind, offset, vaddr, size)) in [(PT_NOTE, note_off, 0, note.len()), (PT_LOAD, load_off, 0x400000, 2)].iter().enumerate() {
+		let ph = 64 + n * 56;
+		core[ph..ph + 4].copy_from_slice(&kind.to_le_bytes());
+		core[ph + 8..ph + 16].copy_from_slice(&(*offset as u64).to_le_bytes());
+		core[ph + 16..ph + 24].copy_from_slice(&(*vaddr as u64).to_le_bytes());
+		core[ph + 32..ph + 40].copy_from_slice(&(*size as u64).to_le_bytes());
+	}
+	let data = parse_core(&core).unwrap();
+	assert_eq!(data.regions, vec![(0x400000, vec![0xaa, 0xbb])]);
+	assert!(data.registers.contains(&("rax".to_string(), 0x2a)));
+	// Offsets that overflow instead of pointing past the end
+	let mut bad = core.clone();
+	bad[64 + 56 + 8..64 + 56 + 16].copy_from_slice(&u64::max_value().to_le_bytes());
+	assert!(parse_core(&bad).is_err());
+	let mut bad = core.clone();
+	bad[32..40].copy_from_slice(&u64::max_value().to_le_bytes());
+	assert!(parse_core(&bad).is_err());
+	assert!(parse_core(b"MZ").is_err());
+	assert!(parse_core(b"\x7fELF\x02").is_err());
+}
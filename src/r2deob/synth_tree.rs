@@ -1,9 +1,19 @@
+extern crate rand;
+use rand::{
+	rngs::StdRng,
+	Rng,
+};
+
 use std::{
-	cmp::Ordering,
 	collections::HashMap,
-	collections::BinaryHeap,
+	fmt,
 	thread,
 	thread::JoinHandle,
+	time::Duration,
+	time::Instant,
+	sync::Arc,
+	sync::atomic::AtomicBool,
+	sync::atomic::Ordering,
 	sync::mpsc::channel,
 	sync::mpsc::Sender,
 	sync::mpsc::Receiver,
@@ -13,24 +23,35 @@ use super::{
 	ast::Expression,
 	calc::Operator,
 	score::Score,
-	sat_interface::Sat,
+	input::{
+		mask,
+		sign_extend,
+	},
+	seeded_rng,
 	BaseT,
 };
 
-#[derive(Debug,PartialEq)]
-struct QueueScore(f32,usize);
+// Non-terminals deeper than this are completed with a register in a playout
+const PLAYOUT_DEPTH: usize = 3;
+// Candidates with more unsolved constants are not considered
+const MAX_CONSTANTS: usize = 2;
+// Partial solutions kept per bit when solving constants
+const SOLVE_BEAM: usize = 4;
+// Tried for a single constant the bitwise solver can not handle, e.g. in comparisons or shifts
+const CONSTANT_GUESSES: [BaseT; 16] = [0, 1, 2, 3, 4, 5, 7, 8, 15, 16, 31, 32, 63, 64, 0xff, -1];
 
 #[derive(Debug,Default)]
 struct WorkerResult {
 	score: Score,
-	node: usize,
+	// Index of the playout the score belongs to
+	id: usize,
 	model: HashMap<String,BaseT>
 }
 
 #[derive(Debug)]
 struct WorkerTask {
 	expression: Expression,
-	node: usize
+	id: usize
 }
 
 #[derive(Debug)]
@@ -38,34 +59,129 @@ struct AtomicWorker {
 	tx: Sender<WorkerTask>,
 	rx: Receiver<WorkerResult>,
 	handle: JoinHandle<()>,
+	pending: usize,
 }
 
 #[derive(Debug)]
 struct Node {
 	expression: Expression,
-	score: f32,
+	// Playouts through this node and the sum of their rewards
+	visits: usize,
+	reward: f32,
 	index: usize,
 	prev: usize,
 	next: Vec<usize>,
 	sat_model: Vec<(String,BaseT)>
 }
 
+#[derive(Debug, Clone)]
+pub struct Settings {
+	// MCTS iterations
+	pub n_runs: usize,
+	pub n_threads: usize,
+	// Give up after this long and return the best candidate so far
+	pub timeout: Option<Duration>,
+	// UCT exploration constant, higher values visit rarely tried subtrees more often
+	pub exploration: f32,
+	// Random completions scored per iteration, the best one is the reward
+	pub n_playouts: usize,
+	// Drives the playouts, engine::synthesize uses the session seed if None
+	pub seed: Option<u64>,
+	// Add constant terminals to the grammar, their values are solved against the traces
+	pub constants: bool,
+	// Distinct best expressions kept and explained in the result
+	pub n_candidates: usize,
+	// Stop once the search tree has this many nodes
+	pub max_nodes: Option<usize>,
+	// Candidates deeper or larger than this are not considered, see Expression::depth and size
+	pub max_depth: Option<usize>,
+	pub max_size: Option<usize>,
+	pub cancel: CancelToken,
+}
+
+// Lets another thread stop a running synthesis, which then returns its best candidate
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+// The budget that ended a synthesis without an exact match
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Budget {
+	Runs,
+	Time,
+	Nodes,
+	Cancelled,
+}
+
+// Outcome of Synthesis::synthesize. Without an exact match expression is the best candidate,
+// or None if no playout scored at all.
+#[derive(Debug, Clone)]
+pub struct SynthesisResult {
+	pub expression: Option<Expression>,
+	pub score: f32,
+	pub exact: bool,
+	pub iterations: usize,
+	// Size of the search tree
+	pub nodes: usize,
+	pub elapsed: Duration,
+	// None if an exact match was found
+	pub exhausted: Option<Budget>,
+	// Best distinct expressions, highest score first; the first one is expression
+	pub candidates: Vec<Candidate>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+	pub expression: Expression,
+	pub score: f32,
+	// Traces the expression gets wrong
+	pub mismatches: Vec<Mismatch>,
+}
+
+// Expected and actual output of one trace, masked to the output width
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+	pub trace: usize,
+	pub expected: BaseT,
+	pub actual: BaseT,
+	pub xor: BaseT,
+}
+
+// Monte Carlo Tree Search over partial expressions, as in Syntia: every iteration selects a leaf
+// with UCT, expands it, scores random completions of it and backpropagates the best score to the root.
 #[derive(Debug)]
 pub struct Synthesis {
 	n_runs: usize,
 	n_threads: usize,
-	n_batchsize: usize,
+	timeout: Option<Duration>,
+	exploration: f32,
+	n_playouts: usize,
+	max_nodes: Option<usize>,
+	max_depth: Option<usize>,
+	max_size: Option<usize>,
+	cancel: CancelToken,
+	rng: StdRng,
+	n_candidates: usize,
+	// Highest scoring distinct playouts so far, best first
+	candidates: Vec<(f32,Expression)>,
 	tree: Vec<Node>,
-	queue: BinaryHeap<QueueScore>,
 	terms: Vec<Expression>,
+	// The Terminal entries of terms
+	registers: Vec<Expression>,
 	scoring: Score,
 }
 
 impl WorkerTask {
-	pub fn work(_sat: Option<&mut Sat>, inputs: &HashMap<String,Vec<BaseT>>, outputs: &Vec<BaseT>, exp: &Expression) -> WorkerResult {
+	// Constants are solved first, their values come back as the model
+	pub fn work(inputs: &HashMap<String,Vec<BaseT>>, outputs: &Vec<BaseT>, width: usize, exp: &Expression) -> WorkerResult {
 		let mut result =  WorkerResult::default();
+		let mut exp = exp.clone();
+		if exp.constants() > 0 && exp.is_finite() {
+			let values = solve_constants(&exp, inputs, outputs, width);
+			result.model = values.iter().enumerate().map(|(n, value)| (format!("c{}", n), *value)).collect();
+			exp = exp.fill(&values);
+		}
 		if let Some(results) = exp.eval(inputs) {
-			result.score = Score::get(&results, outputs);
+			result.score = Score::get(&results, outputs, width);
 		} else {
 			result.score = Score::UnSat;
 		}
@@ -73,76 +189,309 @@ impl WorkerTask {
 	}
 }
 
+// Values of the constants in expression, from left to right. Solved bit by bit from the lowest,
+// which is exact when output bit k only depends on the bits up to k of the constants (add, sub,
+// mul, logic). Otherwise the best of CONSTANT_GUESSES is taken for a single constant.
+fn solve_constants(expression: &Expression, inputs: &HashMap<String,Vec<BaseT>>, outputs: &Vec<BaseT>, width: usize) -> Vec<BaseT> {
+	let n = expression.constants();
+	let bits = ::std::cmp::min(width, 8) * 8;
+	let mut beam: Vec<Vec<BaseT>> = vec![vec![0; n]];
+	for bit in 0..bits {
+		let low_bits = if bit == 63 { !0 } else { (1u64 << (bit + 1)) - 1 };
+		let mut next = Vec::new();
+		for partial in beam.iter() {
+			for set in 0..(1 << n) {
+				let values: Vec<BaseT> = partial.iter().enumerate().map(|(c, value)| value | ((set >> c) & 1) << bit).collect();
+				if next.len() < SOLVE_BEAM && matches_on(&expression.fill(&values), inputs, outputs, low_bits) {
+					next.push(values);
+				}
+			}
+		}
+		beam = next;
+		if beam.is_empty() { break }
+	}
+	// Small negative constants read better sign extended
+	let readable = |value: BaseT| {
+		let signed = sign_extend(value, width);
+		if signed < 0 && signed > -0x100 { signed } else { value }
+	};
+	if let Some(values) = beam.first() {
+		return values.iter().map(|value| readable(*value)).collect()
+	}
+	if n != 1 {
+		return vec![0; n]
+	}
+	let score = |value: &BaseT| match expression.fill(&[*value]).eval(inputs).map(|results| Score::get(&results, outputs, width)) {
+		Some(Score::Combined(x)) => x,
+		_ => 0.0,
+	};
+	let mut best = (score(&CONSTANT_GUESSES[0]), CONSTANT_GUESSES[0]);
+	for value in CONSTANT_GUESSES.iter().skip(1) {
+		let score = score(value);
+		if score > best.0 { best = (score, *value) }
+	}
+	vec![best.1]
+}
+
+// Whether expression gives outputs on the bits in mask for every trace
+fn matches_on(expression: &Expression, inputs: &HashMap<String,Vec<BaseT>>, outputs: &Vec<BaseT>, mask: u64) -> bool {
+	match expression.eval(inputs) {
+		Some(results) => results.iter().zip(outputs).all(|(result, output)| (result ^ output) as u64 & mask == 0),
+		None => false,
+	}
+}
+
 impl Synthesis {
 	pub fn default(registers: &Vec<String>) -> Synthesis {
+		Synthesis::new(registers, &Settings::default())
+	}
+
+	pub fn new(registers: &Vec<String>, settings: &Settings) -> Synthesis {
+		Synthesis::with_operators(registers, settings, &Operator::arithmetic())
+	}
+
+	// Comparisons and logic for flag outputs, see Operator::boolean
+	pub fn boolean(registers: &Vec<String>, settings: &Settings) -> Synthesis {
+		Synthesis::with_operators(registers, settings, &Operator::boolean())
+	}
+
+	pub fn with_operators(registers: &Vec<String>, settings: &Settings, operators: &Vec<Operator>) -> Synthesis {
+		let mut terms = Expression::combinations(registers, operators);
+		if settings.constants {
+			terms.extend(Expression::constant_combinations(operators));
+		}
 		Synthesis {
-			n_runs: 8192,
-			n_threads: 8,
-			n_batchsize: 32,
+			n_runs: settings.n_runs,
+			n_threads: ::std::cmp::max(settings.n_threads, 1),
+			timeout: settings.timeout,
+			exploration: settings.exploration,
+			n_playouts: ::std::cmp::max(settings.n_playouts, 1),
+			max_nodes: settings.max_nodes,
+			max_depth: settings.max_depth,
+			max_size: settings.max_size,
+			cancel: settings.cancel.clone(),
+			rng: seeded_rng(settings.seed.unwrap_or(0)),
+			n_candidates: ::std::cmp::max(settings.n_candidates, 1),
+			candidates: Vec::new(),
 			tree: vec![Node {
 				expression: Expression::NonTerminal,
-				score: 0.0,//Score::UnSat,
+				visits: 0,
+				reward: 0.0,
 				index: 0,
 				prev: 0,
 				next: Vec::new(),
 				sat_model: Vec::new()
 			}],
-			queue: BinaryHeap::from(vec![QueueScore(0.0,0usize)]),
-			terms: Expression::combinations(registers, &vec![Operator::Add, Operator::Sub, Operator::Mul, Operator::Div]),
+			registers: terms.iter().filter(|term| matches!(term, Expression::Terminal(_))).cloned().collect(),
+			terms,
 			scoring: Score::Combined(0.0),
 		}
 	}
 
-	pub fn synthesize(&mut self, inputs: &HashMap<String,Vec<BaseT>>, outputs: &Vec<BaseT>) {
-		let workers = AtomicWorker::setup_workers(self.n_threads, inputs, outputs);
-		for _ in 0..self.n_runs {
-			for w in 0..self.n_threads {
-				if let Some(node) = self.queue.pop() {
-					let derivates = self.tree[node.1].expression.derive(&self.terms);
-					self.create_nodes(&workers[w], derivates, node.1);
-				}
+	// Search until an expression matches every trace or a budget runs out.
+	// Outputs are compared on width bytes. The workers are joined before this returns.
+	pub fn synthesize(&mut self, inputs: &HashMap<String,Vec<BaseT>>, outputs: &[BaseT], width: usize) -> SynthesisResult {
+		let start = Instant::now();
+		let mut workers = AtomicWorker::setup_workers(self.n_threads, inputs, outputs, width);
+		let mut iterations = 0;
+		let exhausted = loop {
+			if let Some(budget) = self.exhausted(iterations, start) { break Some(budget) }
+			iterations += 1;
+			let leaf = self.select();
+			let node = self.expand(leaf);
+			let expression = self.tree[node].expression.clone();
+			let playouts: Vec<Expression> = (0..self.n_playouts).map(|_| self.playout(&expression)).collect();
+			let reward = self.simulate(&mut workers, &playouts, node, start);
+			if self.candidates.first().is_some_and(|(score, _)| *score == 1.0) { break None }
+			self.backpropagate(node, reward);
+		};
+		AtomicWorker::shutdown(workers);
+		let best = self.candidates.first();
+		SynthesisResult {
+			expression: best.map(|(_, expression)| expression.clone()),
+			score: best.map_or(0.0, |(score, _)| *score),
+			exact: best.is_some_and(|(score, _)| *score == 1.0),
+			iterations,
+			nodes: self.tree.len(),
+			elapsed: start.elapsed(),
+			exhausted,
+			candidates: self.candidates.iter()
+				.map(|(score, expression)| Candidate {
+					expression: expression.clone(),
+					score: *score,
+					mismatches: mismatches(expression, inputs, outputs, width),
+				})
+				.collect(),
+		}
+	}
+
+	// Keep expression if it is among the n_candidates best, ties go to the smaller expression, then the earlier one
+	fn remember(&mut self, score: f32, expression: &Expression) {
+		let notation = expression.math_notation();
+		if self.candidates.iter().any(|(_, known)| known.math_notation() == notation) {
+			return
+		}
+		let size = expression.size();
+		let position = self.candidates.iter()
+			.position(|(known, other)| score > *known || (score == *known && size < other.size()))
+			.unwrap_or(self.candidates.len());
+		if position < self.n_candidates {
+			self.candidates.insert(position, (score, expression.clone()));
+			self.candidates.truncate(self.n_candidates);
+		}
+	}
+
+	fn exhausted(&self, iterations: usize, start: Instant) -> Option<Budget> {
+		if let Some(budget) = self.interrupted(start) {
+			Some(budget)
+		} else if iterations >= self.n_runs {
+			Some(Budget::Runs)
+		} else if self.max_nodes.is_some_and(|max| self.tree.len() >= max) {
+			Some(Budget::Nodes)
+		} else {
+			None
+		}
+	}
+
+	// Budgets that can run out in the middle of an iteration
+	fn interrupted(&self, start: Instant) -> Option<Budget> {
+		if self.cancel.is_cancelled() {
+			Some(Budget::Cancelled)
+		} else if self.timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+			Some(Budget::Time)
+		} else {
+			None
+		}
+	}
+
+	fn within_limits(&self, expression: &Expression) -> bool {
+		self.max_depth.is_none_or(|max| expression.depth() <= max) && self.max_size.is_none_or(|max| expression.size() <= max)
+			&& expression.constants() <= MAX_CONSTANTS
+	}
+
+	// Descend from the root along the highest UCT value, children that were never visited come first
+	fn select(&mut self) -> usize {
+		let mut node = 0;
+		while !self.tree[node].next.is_empty() {
+			let unvisited: Vec<usize> = self.tree[node].next.iter().cloned().filter(|next| self.tree[*next].visits == 0).collect();
+			if let Some(next) = self.rng.choose(&unvisited) {
+				return *next
 			}
-			self.update(&workers);
-			self.rebuild_queue();
+			let parent_visits = (self.tree[node].visits as f32).ln();
+			let uct = |child: &Node| child.reward / child.visits as f32 + self.exploration * (parent_visits / child.visits as f32).sqrt();
+			node = *self.tree[node].next.iter()
+				.max_by(|a, b| uct(&self.tree[**a]).partial_cmp(&uct(&self.tree[**b])).unwrap_or(::std::cmp::Ordering::Equal))
+				.unwrap_or(&node);
 		}
+		node
 	}
 
-	fn recv_n_results(n: usize, workers: &Vec<AtomicWorker>) -> Vec<(f32,usize)> {
-		let mut results = Vec::new();
-		for _ in 0..n {
-			for w in 0..workers.len() {
-				if let Ok(result) = &workers[w].rx.try_recv() {
-					match result.score {
-						Score::Combined(x) => results.push((x, result.node)),
-						_ => results.push((0f32, result.node)),
-					}
+	// A leaf is expanded on its second visit, the first playouts run from the leaf itself.
+	// Derivates over the depth or size limit are dropped, the node budget cuts off the rest.
+	fn expand(&mut self, node: usize) -> usize {
+		if self.tree[node].visits == 0 {
+			return node
+		}
+		let room = self.max_nodes.map_or(usize::MAX, |max| max.saturating_sub(self.tree.len()));
+		let derivates: Vec<Expression> = self.tree[node].expression.derive(&self.terms).into_iter()
+			.filter(|expression| self.within_limits(expression))
+			.take(room)
+			.collect();
+		for expression in derivates.iter() {
+			self.add_node(expression, node);
+		}
+		match self.rng.choose(&self.tree[node].next) {
+			Some(next) => *next,
+			// Nothing to derive, the expression has no non-terminals
+			None => node,
+		}
+	}
+
+	// Random completion within the depth and size limits. Tree nodes are within them,
+	// so completing every non-terminal with a register always is.
+	fn playout(&mut self, expression: &Expression) -> Expression {
+		let playout = self.complete(expression, 0);
+		if self.within_limits(&playout) { playout }
+		else { self.complete(expression, PLAYOUT_DEPTH) }
+	}
+
+	// Complete every non-terminal with a register or, with even odds, a random term.
+	// Deep ones always get a register, so playouts stay small.
+	fn complete(&mut self, expression: &Expression, depth: usize) -> Expression {
+		match expression {
+			Expression::NonTerminal => {
+				let terms = if depth >= PLAYOUT_DEPTH || self.rng.gen() { &self.registers } else { &self.terms };
+				match self.rng.choose(terms).cloned() {
+					Some(term) => self.complete(&term, depth + 1),
+					None => Expression::NonTerminal,
 				}
-			}
+			},
+			Expression::Operation(op, a, b) => Expression::Operation(*op, Box::new(self.complete(a, depth + 1)), Box::new(self.complete(b, depth + 1))),
+			terminal => terminal.clone(),
 		}
-		return results
 	}
 
-	fn update(&mut self, workers: &Vec<AtomicWorker>) {
-		for result in Synthesis::recv_n_results(self.n_batchsize, workers) {
-			self.tree[result.1].score = result.0;
-			if result.0 == 1.0 {
-				println!("Candidate found: {}", self.tree[result.1].expression.math_notation());
-				::std::process::exit(0);
+	// Score the playouts on the workers, returns the best score and keeps track of the best playouts
+	// Candidates are kept with their constants filled in. A node without non-terminals is its own
+	// playout, so it also gets the model of its constants.
+	// Playouts go out one per worker at a time, the rest is dropped once the time runs out or on cancel.
+	fn simulate(&mut self, workers: &mut [AtomicWorker], playouts: &[Expression], node: usize, start: Instant) -> f32 {
+		let mut reward = 0f32;
+		for (round, chunk) in playouts.chunks(self.n_threads).enumerate() {
+			if round > 0 && self.interrupted(start).is_some() { break }
+			for (n, expression) in chunk.iter().enumerate() {
+				let worker = &mut workers[n];
+				// A worker that died takes no more tasks, its playouts go unscored
+				if worker.tx.send(WorkerTask { expression: expression.clone(), id: round * self.n_threads + n }).is_ok() {
+					worker.pending += 1;
+				}
+			}
+			for (score, id, model) in Synthesis::recv_results(workers) {
+				let values: Vec<BaseT> = (0..playouts[id].constants()).map(|n| model.get(&format!("c{}", n)).cloned().unwrap_or(0)).collect();
+				self.remember(score, &playouts[id].fill(&values));
+				if self.tree[node].expression.is_finite() && !model.is_empty() {
+					let mut model: Vec<(String,BaseT)> = model.into_iter().collect();
+					model.sort();
+					self.tree[node].sat_model = model;
+				}
+				reward = reward.max(score);
 			}
 		}
+		reward
 	}
 
-	fn rebuild_queue(&mut self) {
-		self.queue.clear();
-		for node in self.tree.iter().filter(|x| x.next.len() < 1).map(|x| (&x.score, &x.index)) {
-			self.queue.push(QueueScore(*node.0, *node.1));
+	fn backpropagate(&mut self, mut node: usize, reward: f32) {
+		loop {
+			self.tree[node].visits += 1;
+			self.tree[node].reward += reward;
+			if node == 0 { break }
+			node = self.tree[node].prev;
 		}
 	}
 
-	fn add_node(&mut self, node: usize, expression: &Expression, parent: usize) {
+	// Wait for every pending task, worker by worker, so the results do not depend on thread timing
+	fn recv_results(workers: &mut [AtomicWorker]) -> Vec<(f32,usize,HashMap<String,BaseT>)> {
+		let mut results = Vec::new();
+		for worker in workers.iter_mut() {
+			for _ in 0..worker.pending {
+				if let Ok(result) = worker.rx.recv() {
+					match result.score {
+						Score::Combined(x) => results.push((x, result.id, result.model)),
+						_ => results.push((0f32, result.id, result.model)),
+					}
+				}
+			}
+			worker.pending = 0;
+		}
+		return results
+	}
+
+	fn add_node(&mut self, expression: &Expression, parent: usize) {
+		let node = self.tree.len();
 		self.tree.push(Node {
 			expression: expression.clone(),
-			score: 0.0,
+			visits: 0,
+			reward: 0.0,
 			index: node,
 			prev: parent,
 			next: Vec::new(),
@@ -150,32 +499,75 @@ impl Synthesis {
 		});
 		self.tree[parent].next.push(node);
 	}
+}
+
+impl fmt::Display for SynthesisResult {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match &self.expression {
+			Some(expression) if self.exact => write!(f, "{}", expression.math_notation()),
+			Some(expression) => write!(f, "? (best: {}, score {:.3})", expression.math_notation(), self.score),
+			None => write!(f, "?"),
+		}
+	}
+}
 
-	fn create_nodes(&mut self, worker: &AtomicWorker, derivates: Vec<Expression>, parent: usize) {
-			for expression in derivates.iter() {
-				let last_node = self.tree.len();
-				worker.tx.send(WorkerTask{expression: expression.clone(), node: last_node}).unwrap();
-				self.add_node(last_node, expression, parent);
+impl SynthesisResult {
+	// Every candidate with the traces it fails, at most max_traces of them each
+	pub fn explain(&self, max_traces: usize) -> String {
+		let mut lines = Vec::new();
+		for candidate in self.candidates.iter() {
+			lines.push(format!("{:.3} {}: {} traces differ", candidate.score, candidate.expression.math_notation(), candidate.mismatches.len()));
+			for mismatch in candidate.mismatches.iter().take(max_traces) {
+				lines.push(format!("  trace {}: expected 0x{:x}, got 0x{:x}, xor 0x{:x}", mismatch.trace, mismatch.expected, mismatch.actual, mismatch.xor));
 			}
+			if candidate.mismatches.len() > max_traces {
+				lines.push("  ...".to_string());
+			}
+		}
+		lines.join("\n")
+	}
+}
+
+// Traces where expression and outputs differ on width bytes, every trace if it does not evaluate
+fn mismatches(expression: &Expression, inputs: &HashMap<String,Vec<BaseT>>, outputs: &[BaseT], width: usize) -> Vec<Mismatch> {
+	let mask = mask(width) as BaseT;
+	let actual = expression.eval(inputs).unwrap_or_else(|| vec![0; outputs.len()]);
+	outputs.iter().zip(actual).enumerate()
+		.map(|(trace, (expected, actual))| Mismatch { trace, expected: expected & mask, actual: actual & mask, xor: (expected ^ actual) & mask })
+		.filter(|mismatch| mismatch.xor != 0)
+		.collect()
+}
+
+impl CancelToken {
+	pub fn new() -> CancelToken {
+		CancelToken::default()
+	}
+
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::SeqCst)
 	}
 }
 
 impl AtomicWorker {	
-	fn setup_workers(n_workers: usize, inputs: &HashMap<String,Vec<BaseT>>, outputs: &Vec<BaseT>) -> Vec<AtomicWorker> {
+	fn setup_workers(n_workers: usize, inputs: &HashMap<String,Vec<BaseT>>, outputs: &[BaseT], width: usize) -> Vec<AtomicWorker> {
 		let mut result: Vec<AtomicWorker> = Vec::new();
 		for _ in 0..n_workers {
 			let (task_tx, task_rx) = channel::<WorkerTask>();
 			let (result_tx, result_rx) = channel::<WorkerResult>();
 			let input = inputs.clone();
-			let output = outputs.clone();
+			let output = outputs.to_vec();
 			let handle = thread::spawn(move|| {
-				let mut sat = Sat::init();
 				loop {
 					if let Ok(task) = task_rx.recv() {
-						let mut result = WorkerTask::work(Some(&mut sat), &input, &output, &task.expression);
-						result.node = task.node;
-						if let Err(x) = result_tx.send(result) {
-							panic!("worker send failure: {:?}", x);
+						let mut result = WorkerTask::work(&input, &output, width, &task.expression);
+						result.id = task.id;
+						// Receiver is gone once the synthesis has finished
+						if result_tx.send(result).is_err() {
+							break;
 						}
 					} else {
 						break;
@@ -186,24 +578,38 @@ impl AtomicWorker {
 				tx: task_tx,
 				rx: result_rx,
 				handle: handle,
+				pending: 0,
 			});
 		}
 		return result
 	}
-}
 
-impl Eq for QueueScore {}
-
-impl PartialOrd for QueueScore {
-	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-		other.0.partial_cmp(&self.0)
+	// Closing the task channel ends the worker loop, then wait for the thread
+	fn shutdown(workers: Vec<AtomicWorker>) {
+		for AtomicWorker { tx, handle, .. } in workers {
+			drop(tx);
+			let _ = handle.join();
+		}
 	}
 }
 
-impl Ord for QueueScore {
-    fn cmp(&self, other: &QueueScore) -> Ordering {
-        self.partial_cmp(other).unwrap()
-    }
+impl Default for Settings {
+	fn default() -> Self {
+		Settings {
+			n_runs: 8192,
+			n_threads: 8,
+			timeout: None,
+			exploration: 1.5,
+			n_playouts: 8,
+			seed: None,
+			constants: true,
+			n_candidates: 5,
+			max_nodes: None,
+			max_depth: None,
+			max_size: None,
+			cancel: CancelToken::new(),
+		}
+	}
 }
 
 #[test]
@@ -222,6 +628,150 @@ fn worker_test_finite_perfect_expression() {
 	inputs.insert("rax".to_string(), vec![1,2,3,4,5,6,7,8]);
 	inputs.insert("rbx".to_string(), vec![1,2,3,4,5,6,7,8]);
 	inputs.insert("rcx".to_string(), vec![1,2,3,4,5,6,7,8]);
-	let result = WorkerTask::work(None, &inputs, &vec![1,2,3,4,5,6,7,8], &ast);
+	let result = WorkerTask::work(&inputs, &vec![1,2,3,4,5,6,7,8], 8, &ast);
 	assert_eq!(result.score, Score::Combined(1.0))
 }
+
+#[test]
+fn synthesis_test_boolean() {
+	let mut inputs = HashMap::new();
+	inputs.insert("edi".to_string(), vec![1, 7, -3, 4, 0, 9, -8, 2, 5]);
+	inputs.insert("esi".to_string(), vec![2, 7, 5, -1, 3, 1, -9, 6, 5]);
+	let outputs = vec![1, 0, 1, 0, 1, 0, 0, 1, 0];
+	let settings = Settings { n_runs: 16, n_threads: 2, seed: Some(1), constants: false, ..Default::default() };
+	let mut synthesis = Synthesis::boolean(&vec!["edi".to_string(), "esi".to_string()], &settings);
+	let result = synthesis.synthesize(&inputs, &outputs, 1);
+	assert!(result.exact);
+	assert_eq!(result.score, 1.0);
+	assert_eq!(result.to_string(), "(edi <s esi)");
+}
+
+#[test]
+fn synthesis_test_mcts() {
+	let mut inputs = HashMap::new();
+	inputs.insert("edi".to_string(), vec![1, 7, -3, 4, 0, 9, -8, 2]);
+	inputs.insert("esi".to_string(), vec![2, 7, 5, -1, 3, 1, -9, 6]);
+	let outputs = vec![5, 21, 7, 2, 6, 11, -26, 14];
+	let registers = vec!["edi".to_string(), "esi".to_string()];
+	let settings = Settings { n_runs: 256, n_threads: 2, seed: Some(5), ..Default::default() };
+	let mut synthesis = Synthesis::new(&registers, &settings);
+	let result = synthesis.synthesize(&inputs, &outputs, 8);
+	assert!(result.exact);
+	assert_eq!(result.nodes, synthesis.tree.len());
+	assert!(result.iterations > 0 && result.iterations <= 256);
+	let expression = result.expression.unwrap();
+	assert_eq!(expression.eval(&inputs).unwrap(), outputs);
+	// Every iteration backpropagates through the root
+	assert!(synthesis.tree[0].visits > 0);
+	assert_eq!(synthesis.tree[0].visits, synthesis.tree[0].next.iter().map(|next| synthesis.tree[*next].visits).sum::<usize>() + 1);
+	let again = Synthesis::new(&registers, &settings).synthesize(&inputs, &outputs, 8);
+	assert_eq!(expression.math_notation(), again.expression.unwrap().math_notation());
+}
+
+#[test]
+fn synthesis_test_not_found() {
+	let mut inputs = HashMap::new();
+	inputs.insert("edi".to_string(), vec![1, 2, 3, 4]);
+	let outputs = vec![7, 1, 9, 2];
+	let settings = Settings { n_runs: 4, n_threads: 1, seed: Some(1), ..Default::default() };
+	let result = Synthesis::new(&vec!["edi".to_string()], &settings).synthesize(&inputs, &outputs, 8);
+	assert!(!result.exact);
+	assert_eq!(result.iterations, 4);
+	assert_eq!(result.exhausted, Some(Budget::Runs));
+	assert!(result.expression.is_some());
+	assert!(result.to_string().starts_with("? (best: "));
+}
+
+#[test]
+fn synthesis_test_budgets() {
+	let mut inputs = HashMap::new();
+	inputs.insert("edi".to_string(), vec![1, 2, 3, 4]);
+	inputs.insert("esi".to_string(), vec![5, 3, 8, 1]);
+	let outputs = vec![7, 1, 9, 2];
+	let registers = vec!["edi".to_string(), "esi".to_string()];
+
+	let settings = Settings { n_runs: 64, n_threads: 2, seed: Some(1), max_nodes: Some(40), ..Default::default() };
+	let mut synthesis = Synthesis::new(&registers, &settings);
+	let result = synthesis.synthesize(&inputs, &outputs, 8);
+	assert_eq!(result.exhausted, Some(Budget::Nodes));
+	assert_eq!(result.nodes, 40);
+
+	let settings = Settings { n_runs: 64, n_threads: 2, seed: Some(1), max_depth: Some(2), max_size: Some(3), ..Default::default() };
+	let mut synthesis = Synthesis::new(&registers, &settings);
+	let result = synthesis.synthesize(&inputs, &outputs, 8);
+	assert!(synthesis.tree.iter().all(|node| node.expression.depth() <= 2));
+	assert!(result.expression.unwrap().size() <= 3);
+
+	let settings = Settings { n_threads: 2, ..Default::default() };
+	settings.cancel.cancel();
+	let result = Synthesis::new(&registers, &settings).synthesize(&inputs, &outputs, 8);
+	assert_eq!(result.exhausted, Some(Budget::Cancelled));
+	assert_eq!(result.iterations, 0);
+	// Zero threads still get one worker
+	let settings = Settings { n_runs: 4, n_threads: 0, ..Default::default() };
+	let result = Synthesis::new(&registers, &settings).synthesize(&inputs, &outputs, 8);
+	assert_eq!(result.exhausted, Some(Budget::Runs));
+}
+
+#[test]
+fn synthesis_test_candidates() {
+	let mut inputs = HashMap::new();
+	inputs.insert("edi".to_string(), vec![1, 2, 3, 4]);
+	inputs.insert("esi".to_string(), vec![5, 3, 8, 1]);
+	// edi + esi, except for the last trace
+	let outputs = vec![6, 5, 11, 6];
+	let registers = vec!["edi".to_string(), "esi".to_string()];
+	let settings = Settings { n_runs: 64, n_threads: 2, seed: Some(2), n_candidates: 3, max_depth: Some(2), ..Default::default() };
+	let result = Synthesis::new(&registers, &settings).synthesize(&inputs, &outputs, 4);
+	assert!(!result.exact);
+	assert_eq!(result.candidates.len(), 3);
+	assert!(result.candidates.windows(2).all(|pair| pair[0].score >= pair[1].score));
+	assert_ne!(result.candidates[0].expression.math_notation(), result.candidates[1].expression.math_notation());
+	let best = result.candidates.iter().find(|candidate| candidate.expression.math_notation() == "(edi + esi)").unwrap();
+	assert_eq!(best.mismatches, vec![Mismatch { trace: 3, expected: 6, actual: 5, xor: 3 }]);
+	assert!(result.explain(4).contains("trace 3: expected 0x6, got 0x5, xor 0x3"));
+
+	let expression = Expression::Terminal("edi".to_string());
+	assert_eq!(mismatches(&expression, &inputs, &vec![1, 2, 0x1_0000_0003, 5], 4).len(), 1);
+}
+
+#[test]
+fn synthesis_test_constants() {
+	let edi: Vec<BaseT> = vec![1, 7, 0x1234, 4, 0, 0xffff_fff0, 0x8000_0000, 2, 0x55aa];
+	let mut inputs = HashMap::new();
+	inputs.insert("edi".to_string(), edi.clone());
+	let outputs: Vec<BaseT> = edi.iter().map(|x| (x.wrapping_mul(0x9e37_79b9) + 7) & 0xffff_ffff).collect();
+	let magic = Expression::Operation(
+		Operator::Add,
+		Box::new(Expression::Operation(Operator::Mul, Box::new(Expression::Terminal("edi".to_string())), Box::new(Expression::Constant(None)))),
+		Box::new(Expression::Constant(None))
+	);
+	assert_eq!(solve_constants(&magic, &inputs, &outputs, 4), vec![0x9e37_79b9, 7]);
+	let result = WorkerTask::work(&inputs, &outputs, 4, &magic);
+	assert_eq!(result.score, Score::Combined(1.0));
+	assert_eq!(result.model["c0"], 0x9e37_79b9);
+
+	// Not bitwise solvable, falls back to the guesses
+	let outputs: Vec<BaseT> = edi.iter().map(|x| ((x & 0xffff_ffff) < 5) as BaseT).collect();
+	let compare = Expression::Operation(Operator::Ult, Box::new(Expression::Terminal("edi".to_string())), Box::new(Expression::Constant(None)));
+	assert_eq!(solve_constants(&compare, &inputs, &outputs, 1), vec![5]);
+
+	let outputs: Vec<BaseT> = edi.iter().map(|x| (x - 3) & 0xffff_ffff).collect();
+	let settings = Settings { n_runs: 256, n_threads: 2, seed: Some(4), max_depth: Some(2), ..Default::default() };
+	let mut synthesis = Synthesis::new(&vec!["edi".to_string()], &settings);
+	let result = synthesis.synthesize(&inputs, &outputs, 4);
+	assert!(result.exact);
+	let expression = result.expression.unwrap();
+	assert_eq!(expression.eval(&inputs).unwrap().iter().map(|x| x & 0xffff_ffff).collect::<Vec<_>>(), outputs);
+	assert!(expression.math_notation().contains('3'));
+
+	// The model of a node without non-terminals is stored with it
+	let minus = Expression::Operation(Operator::Add, Box::new(Expression::Terminal("edi".to_string())), Box::new(Expression::Constant(None)));
+	let mut synthesis = Synthesis::new(&vec!["edi".to_string()], &settings);
+	synthesis.add_node(&minus, 0);
+	let mut workers = AtomicWorker::setup_workers(2, &inputs, &outputs, 4);
+	assert_eq!(synthesis.simulate(&mut workers, &[minus], 1, Instant::now()), 1.0);
+	AtomicWorker::shutdown(workers);
+	assert_eq!(synthesis.tree[1].sat_model, vec![("c0".to_string(), -3)]);
+	assert_eq!(synthesis.candidates[0].1.math_notation(), "(edi + -3)");
+}
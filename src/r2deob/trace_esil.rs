@@ -0,0 +1,520 @@
+extern crate r2pipe;
+use r2pipe::R2Pipe;
+
+use super::{
+	engine::{
+		parse_hex,
+		r2_cmd,
+		r2_cmdj,
+		resolve_addr,
+		resolve_stop_addrs,
+		spawn_r2,
+		CallStub,
+		FcnConfig,
+		StopCondition,
+		StopReason,
+		Trace,
+		Tracer,
+	},
+	location::Location,
+	reg_profile::RegProfile,
+	snapshot::{
+		Snapshot,
+		SnapshotData,
+	},
+	splitmix,
+	R2Error,
+	BaseT,
+};
+
+use std::collections::HashMap;
+
+// Scratch memory for pointer outputs, one slot per output. It goes to the first
+// page aligned gap at or above SCRATCH_ADDR that no other map overlaps.
+const SCRATCH_ADDR: u64 = 0x0020_0000;
+const SCRATCH_SLOT: u64 = 0x100;
+const PAGE_SIZE: u64 = 0x1000;
+
+// ESIL only reports faults through these hooks, each one flags the PC it fired at
+const FAULT_HOOKS: [(&str, &str, &str); 3] = [
+	("cmd.esil.trap", "r2deob.trap", "trap"),
+	("cmd.esil.ioer", "r2deob.ioer", "invalid memory access"),
+	("cmd.esil.todo", "r2deob.todo", "unimplemented ESIL"),
+];
+
+// Snapshot memory is written with wx in chunks of this many bytes
+const SNAPSHOT_CHUNK: usize = 0x1000;
+
+// Callees with a built-in model for CallStub::Libc
+const LIBC_MODELS: [&str; 4] = ["strlen", "memcpy", "abs", "rand"];
+
+// Generates traces by emulating the target with ESIL
+pub struct EsilTracer {
+	r2: R2Pipe,
+	loc: String,
+	stop: StopCondition,
+	stop_addrs: Vec<u64>,
+	max_steps: usize,
+	profile: RegProfile,
+	stubs: Stubs,
+	// Address of the scratch memory for pointer outputs
+	scratch: u64,
+	// Register values of the snapshot, set before the inputs
+	snapshot_regs: Vec<(String,u64)>,
+	// Snapshot memory is mapped, ESIL writes to it go to the io cache
+	snapshot_mem: bool,
+	// Address and register of every probe, with the value of the current run
+	probes: Vec<(u64,String)>,
+	probe_values: Vec<Option<BaseT>>,
+}
+
+// Call stubs resolved to their target address, with the registers needed to skip a call
+struct Stubs {
+	targets: HashMap<u64,(String,CallStub)>,
+	any: Option<CallStub>,
+	pc: String,
+	ret: String,
+	args: Vec<String>,
+	// Return values of the current trace
+	values: Vec<(String,BaseT)>,
+	// Seeded from the inputs, so rand gives the same values on a rerun
+	rand_state: u64,
+}
+
+impl Tracer for EsilTracer {
+	// Spawn r2pipe, init esil
+	fn init(fcn: &FcnConfig, inputs: &[Location], outputs: &[Location]) -> Result<EsilTracer, R2Error> {
+		let mut r2pipe = spawn_r2(fcn)?;
+		r2_cmd(&mut r2pipe, "aei;aeim")?;
+
+		// aer silently ignores unknown registers
+		let profile = RegProfile::load(&mut r2pipe)?;
+		for location in inputs.iter().chain(outputs) {
+			match location {
+				Location::Register(reg) | Location::Pointer(reg, _) if profile.get(reg).is_none() => return Err(R2Error::UnknownRegister(reg.clone())),
+				_ => {},
+			}
+		}
+
+		let snapshot = match &fcn.snapshot {
+			Some(snapshot) => snapshot.load()?,
+			None => SnapshotData::default(),
+		};
+		map_snapshot(&mut r2pipe, &snapshot)?;
+
+		// After the snapshot, so the scratch memory avoids its regions too
+		let size = SCRATCH_SLOT * outputs.len() as u64;
+		let maps = r2_cmdj(&mut r2pipe, "omj")?;
+		let maps: Vec<(u64,u64)> = maps.as_array().map_or(Vec::new(), |maps| maps.iter()
+			.filter_map(|map| Some((map["from"].as_u64()?, map["to"].as_u64()?)))
+			.collect());
+		let scratch = free_addr(&maps, SCRATCH_ADDR, size);
+		r2_cmd(&mut r2pipe, &format!("o malloc://{} {}", size, scratch))?;
+		// Core dumps carry registers like fs_base that r2 may not know, PC is set by aeip
+		let pc = profile.get("PC").map(|reg| reg.name.clone());
+		let mut snapshot_regs = Vec::new();
+		for (reg, value) in snapshot.registers.iter() {
+			match profile.get(reg) {
+				Some(known) if Some(&known.name) == pc.as_ref() => {},
+				Some(_) => snapshot_regs.push((reg.clone(), *value)),
+				None => if let Some(Snapshot::Json(_)) = fcn.snapshot { return Err(R2Error::UnknownRegister(reg.clone())) },
+			}
+		}
+
+		r2_cmd(&mut r2pipe, "e esil.iotrap=true")?;
+		for (hook, flag, _) in FAULT_HOOKS.iter() {
+			r2_cmd(&mut r2pipe, &format!("\"e {}=f {} @r:PC\"", hook, flag))?;
+		}
+
+		let stop_addrs = resolve_stop_addrs(&mut r2pipe, &fcn.stop)?;
+		let mut probes = Vec::new();
+		for probe in fcn.probes.iter() {
+			if profile.get(&probe.reg).is_none() { return Err(R2Error::UnknownRegister(probe.reg.clone())) }
+			probes.push((resolve_addr(&mut r2pipe, &probe.loc)?, probe.reg.clone()));
+		}
+		let stubs = Stubs::init(&mut r2pipe, &profile, fcn)?;
+
+		Ok(EsilTracer {
+			r2: r2pipe,
+			loc: fcn.loc.clone(),
+			stop: fcn.stop.clone(),
+			stop_addrs,
+			max_steps: fcn.max_steps,
+			profile,
+			stubs,
+			scratch,
+			snapshot_regs,
+			snapshot_mem: !snapshot.regions.is_empty(),
+			probe_values: vec![None; probes.len()],
+			probes,
+		})
+	}
+
+	fn trace(&mut self, inputs: &[Location], values: &[BaseT], outputs: &[Location]) -> Result<Trace, R2Error> {
+		// Flush old stuff and seek to target fcn
+		let cmd = "aek-;s ".to_string() + &self.loc;
+		r2_cmd(&mut self.r2, &cmd)?;
+		// Drop the writes of the last trace to the snapshot memory
+		if self.snapshot_mem {
+			r2_cmd(&mut self.r2, "wcr")?;
+		}
+		// Init esil
+		r2_cmd(&mut self.r2, "aei;aeim;aeip")?;
+		for (_, flag, _) in FAULT_HOOKS.iter() {
+			r2_cmd(&mut self.r2, &format!("f-{}", flag))?;
+		}
+		for (reg, value) in self.snapshot_regs.iter() {
+			r2_cmd(&mut self.r2, &format!("aer {} = 0x{:x}", reg, value))?;
+		}
+		self.stubs.values.clear();
+		self.stubs.rand_state = values.iter().fold(0, |state, value| splitmix(state ^ *value as u64));
+		// Set input
+		for (location, value) in inputs.iter().zip(values) {
+			if let Location::Register(reg) = location {
+				let cmd = format!("aer {} = 0x{:x}", reg, *value as u64);
+				r2_cmd(&mut self.r2, &cmd)?;
+			}
+		}
+		// Memory inputs last, their addresses may depend on register state
+		for (location, value) in inputs.iter().zip(values) {
+			if let Location::Memory(addr, size) = location {
+				let addr = self.resolve_mem_addr(addr)?;
+				let cmd = format!("wv{} 0x{:x} @ {}", size, *value as u64, addr);
+				r2_cmd(&mut self.r2, &cmd)?;
+			}
+		}
+		// Fix memory output addresses at fcn entry
+		let output_addrs = self.setup_outputs(outputs)?;
+		// Run
+		let stop = self.run()?;
+		self.check_faults()?;
+		// Fetch results
+		let regs = r2_cmdj(&mut self.r2, "aerj")?;
+		let mut result = Vec::new();
+		for (location, addr) in outputs.iter().zip(output_addrs) {
+			match (location, addr) {
+				(Location::Register(reg), _) => result.push(self.read_reg(&regs, reg)? as BaseT),
+				(Location::Memory(_, size), Some(addr)) | (Location::Pointer(_, size), Some(addr)) => {
+					result.push(self.read_mem(addr, *size)?);
+				},
+				_ => return Err(R2Error::Config(format!("no address for output: {}", location))),
+			}
+		}
+		Ok(Trace { outputs: result, stop, stubs: self.stubs.values.clone(), probes: self.probe_values.clone() })
+	}
+
+	fn width(&self, location: &Location) -> usize {
+		match location {
+			Location::Register(reg) => self.profile.width(reg).unwrap_or(8),
+			_ => location.width(),
+		}
+	}
+
+	fn is_flag(&self, location: &Location) -> bool {
+		match location {
+			Location::Register(reg) => self.profile.get(reg).is_some_and(|reg| reg.size == 1),
+			_ => false,
+		}
+	}
+}
+
+impl EsilTracer {
+	// Resolve the address of each memory output and point pointer outputs to zeroed scratch memory
+	fn setup_outputs(&mut self, outputs: &[Location]) -> Result<Vec<Option<u64>>, R2Error> {
+		let mut addrs = Vec::new();
+		for (n, location) in outputs.iter().enumerate() {
+			match location {
+				Location::Register(_) => addrs.push(None),
+				Location::Memory(addr, _) => addrs.push(Some(self.resolve_mem_addr(addr)?)),
+				Location::Pointer(reg, _) => {
+					let addr = self.scratch + SCRATCH_SLOT * n as u64;
+					let cmd = format!("w0 {} @ {};aer {} = {}", SCRATCH_SLOT, addr, reg, addr);
+					r2_cmd(&mut self.r2, &cmd)?;
+					addrs.push(Some(addr));
+				},
+			}
+		}
+		Ok(addrs)
+	}
+
+	// aerj only lists full registers, sub-registers are cut out of their parent
+	fn read_reg(&self, regs: &serde_json::Value, reg: &str) -> Result<u64, R2Error> {
+		if let Some(value) = regs[reg].as_u64() {
+			return Ok(value)
+		}
+		let parent = if let Some(parent) = self.profile.parent(reg) { parent }
+		else { return Err(R2Error::UnknownRegister(reg.to_string())) };
+		match regs[parent.name.as_str()].as_u64().and_then(|value| self.profile.extract(reg, value)) {
+			Some(value) => Ok(value),
+			None => Err(R2Error::Parse { cmd: "aerj".to_string(), output: regs.to_string() }),
+		}
+	}
+
+	fn read_mem(&mut self, addr: u64, size: usize) -> Result<BaseT, R2Error> {
+		let cmd = format!("pv{} @ {}", size, addr);
+		let res = r2_cmd(&mut self.r2, &cmd)?;
+		Ok(parse_hex(&cmd, &res)? as BaseT)
+	}
+
+	// Resolve address expressions like "rsp+8" against the current ESIL registers
+	fn resolve_mem_addr(&mut self, addr: &str) -> Result<u64, R2Error> {
+		let (base, offset) = Location::split_addr(addr);
+		let regs = r2_cmdj(&mut self.r2, "aerj")?;
+		let base = if let Some(value) = regs[base].as_u64() { value }
+		else { resolve_addr(&mut self.r2, base)? };
+		Ok((base as i64).wrapping_add(offset) as u64)
+	}
+
+	// Step until the stop condition fires or max_steps is exceeded.
+	// The instruction at the stop location is not executed.
+	// Ret only fires on the return from the traced fcn, not on one from a callee.
+	fn run(&mut self) -> Result<StopReason, R2Error> {
+		self.probe_values = vec![None; self.probes.len()];
+		let mut depth = 0;
+		for _ in 0..self.max_steps {
+			let cmd = "aoj @r:PC";
+			let op = r2_cmdj(&mut self.r2, cmd)?;
+			// Nothing decodes at PC, e.g. after a jump into unmapped memory
+			let addr = if let Some(addr) = op[0]["addr"].as_u64() { addr }
+			else { return Err(R2Error::Emulation { cmd: cmd.to_string(), output: op.to_string() }) };
+			self.record_probes(addr)?;
+			if self.stop_addrs.contains(&addr) {
+				return Ok(StopReason::Address(addr))
+			}
+			if ["ill", "invalid", "unk"].contains(&op[0]["type"].as_str().unwrap_or("")) {
+				return Err(R2Error::Emulation { cmd: cmd.to_string(), output: format!("unsupported instruction at 0x{:x}: {}", addr, op[0]["opcode"]) })
+			}
+			let kind = op[0]["type"].as_str().unwrap_or("");
+			let is_call = kind.ends_with("call");
+			if let StopCondition::Ret = self.stop {
+				if call_depth(depth, kind, false).is_none() {
+					return Ok(StopReason::Ret)
+				}
+			}
+			if is_call && self.stub_call(&op[0], addr)? {
+				continue
+			}
+			r2_cmd(&mut self.r2, "aes")?;
+			if let StopCondition::Ret = self.stop {
+				let taken = is_call && self.pc()? != addr + op[0]["size"].as_u64().unwrap_or(0);
+				depth = call_depth(depth, kind, taken).unwrap_or(0);
+			}
+		}
+		Ok(StopReason::MaxSteps)
+	}
+
+	fn pc(&mut self) -> Result<u64, R2Error> {
+		let regs = r2_cmdj(&mut self.r2, "aerj")?;
+		match self.profile.get("PC") {
+			Some(pc) => self.read_reg(&regs, &pc.name),
+			None => Err(R2Error::UnknownRegister("PC".to_string())),
+		}
+	}
+
+	// Record the probes at addr that were not hit before in this run
+	fn record_probes(&mut self, addr: u64) -> Result<(), R2Error> {
+		if !self.probes.iter().zip(&self.probe_values).any(|((probe, _), value)| *probe == addr && value.is_none()) {
+			return Ok(())
+		}
+		let regs = r2_cmdj(&mut self.r2, "aerj")?;
+		for n in 0..self.probes.len() {
+			if self.probes[n].0 == addr && self.probe_values[n].is_none() {
+				self.probe_values[n] = Some(self.read_reg(&regs, &self.probes[n].1)? as BaseT);
+			}
+		}
+		Ok(())
+	}
+
+	// Skip the call at addr if its target is stubbed: set the return register and continue after the call
+	fn stub_call(&mut self, op: &serde_json::Value, addr: u64) -> Result<bool, R2Error> {
+		let (name, stub) = match op["jump"].as_u64().and_then(|target| self.stubs.targets.get(&target)) {
+			Some((name, stub)) => (name.clone(), stub.clone()),
+			None => match &self.stubs.any {
+				Some(stub) => ("call".to_string(), stub.clone()),
+				None => return Ok(false),
+			},
+		};
+		let regs = r2_cmdj(&mut self.r2, "aerj")?;
+		let mut args = Vec::new();
+		for reg in self.stubs.args.iter() {
+			args.push(self.read_reg(&regs, reg)? as BaseT);
+		}
+		let value = match stub {
+			CallStub::Constant(value) => value,
+			CallStub::Closure(f) => f(&args),
+			CallStub::Libc => self.libc(&name, &args)?,
+		};
+		let next = addr + op["size"].as_u64().unwrap_or(0);
+		let cmd = format!("aer {} = 0x{:x};aer {} = 0x{:x}", self.stubs.ret, value as u64, self.stubs.pc, next);
+		r2_cmd(&mut self.r2, &cmd)?;
+		self.stubs.values.push((format!("{}@0x{:x}", name, addr), value));
+		Ok(true)
+	}
+
+	// Built-in models of LIBC_MODELS, memory effects go to the ESIL memory
+	fn libc(&mut self, name: &str, args: &[BaseT]) -> Result<BaseT, R2Error> {
+		let nargs = match name { "rand" => 0, "strlen" | "abs" => 1, _ => 3 };
+		if args.len() < nargs {
+			return Err(R2Error::Config(format!("{} needs {} argument registers", name, nargs)))
+		}
+		match name {
+			"strlen" => {
+				let cmd = format!("p8 4096 @ 0x{:x}", args[0] as u64);
+				let hex = r2_cmd(&mut self.r2, &cmd)?;
+				let hex = hex.trim().as_bytes();
+				Ok(hex.chunks(2).position(|byte| byte == b"00").unwrap_or(hex.len() / 2) as BaseT)
+			},
+			"memcpy" => {
+				let cmd = format!("y {} @ 0x{:x};yy @ 0x{:x}", args[2] as u64, args[1] as u64, args[0] as u64);
+				r2_cmd(&mut self.r2, &cmd)?;
+				Ok(args[0])
+			},
+			"abs" => Ok((args[0] as i32).wrapping_abs() as u32 as BaseT),
+			"rand" => {
+				self.stubs.rand_state = splitmix(self.stubs.rand_state);
+				Ok((self.stubs.rand_state & 0x7fff_ffff) as BaseT)
+			},
+			_ => Err(R2Error::Config(format!("no libc model for {}", name))),
+		}
+	}
+
+	// Faults flagged by FAULT_HOOKS during the last run
+	fn check_faults(&mut self) -> Result<(), R2Error> {
+		for (hook, flag, fault) in FAULT_HOOKS.iter() {
+			let cmd = format!("?v {}", flag);
+			let res = r2_cmd(&mut self.r2, &cmd)?;
+			match parse_hex(&cmd, &res)? {
+				0 => {},
+				addr => return Err(R2Error::Emulation { cmd: hook.to_string(), output: format!("{} at 0x{:x}", fault, addr) }),
+			}
+		}
+		Ok(())
+	}
+}
+
+// Map every snapshot region at its address. Later maps take precedence in r2, so the snapshot
+// hides the binary where they overlap. Writes after this go to the io cache and can be reset.
+fn map_snapshot(r2: &mut R2Pipe, snapshot: &SnapshotData) -> Result<(), R2Error> {
+	if snapshot.regions.is_empty() {
+		return Ok(())
+	}
+	for (addr, bytes) in snapshot.regions.iter() {
+		r2_cmd(r2, &format!("o malloc://{} 0x{:x}", bytes.len(), addr))?;
+		for (n, chunk) in bytes.chunks(SNAPSHOT_CHUNK).enumerate() {
+			let hex: String = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+			r2_cmd(r2, &format!("wx {} @ 0x{:x}", hex, addr + (n * SNAPSHOT_CHUNK) as u64))?;
+		}
+	}
+	r2_cmd(r2, "e io.cache=true")?;
+	Ok(())
+}
+
+impl Stubs {
+	fn init(r2: &mut R2Pipe, profile: &RegProfile, fcn: &FcnConfig) -> Result<Stubs, R2Error> {
+		let mut stubs = Stubs {
+			targets: HashMap::new(),
+			any: None,
+			pc: String::new(),
+			ret: String::new(),
+			args: Vec::new(),
+			values: Vec::new(),
+			rand_state: 0,
+		};
+		if fcn.call_stubs.is_empty() {
+			return Ok(stubs)
+		}
+		stubs.pc = if let Some(reg) = profile.get("PC") { reg.name.clone() }
+		else { return Err(R2Error::UnknownRegister("PC".to_string())) };
+		// Older register profiles have no R0 alias, fall back to the default calling convention
+		stubs.ret = match profile.get("R0") {
+			Some(reg) => reg.name.clone(),
+			None => {
+				let cc = r2_cmd(r2, "k anal/cc/default.cc")?;
+				r2_cmd(r2, &format!("k anal/cc/cc.{}.ret", cc.trim()))?.trim().to_string()
+			},
+		};
+		if profile.get(&stubs.ret).is_none() {
+			return Err(R2Error::UnknownRegister(stubs.ret))
+		}
+		while let Some(reg) = profile.get(&format!("A{}", stubs.args.len())) {
+			stubs.args.push(reg.name.clone());
+		}
+
+		for (target, stub) in fcn.call_stubs.iter() {
+			if target == "*" {
+				// Libc picks its model by callee name, most callees behind the wildcard have none
+				if let CallStub::Libc = stub {
+					return Err(R2Error::Config("no libc model for *, stub the modelled callees by name".to_string()))
+				}
+				stubs.any = Some(stub.clone());
+				continue
+			}
+			let name = callee_name(target);
+			if let CallStub::Libc = stub {
+				if !LIBC_MODELS.contains(&name.as_str()) {
+					return Err(R2Error::Config(format!("no libc model for {}", target)))
+				}
+			}
+			stubs.targets.insert(resolve_addr(r2, target)?, (name, stub.clone()));
+		}
+		Ok(stubs)
+	}
+}
+
+// First page aligned address at or above start where size bytes overlap none of the maps.
+// Map ranges are inclusive like r2 prints them.
+fn free_addr(maps: &[(u64,u64)], start: u64, size: u64) -> u64 {
+	let mut addr = start;
+	while let Some((_, to)) = maps.iter().find(|(from, to)| *from < addr.saturating_add(size) && *to >= addr) {
+		let next = to.saturating_add(PAGE_SIZE) & !(PAGE_SIZE - 1);
+		if next <= addr { break }
+		addr = next;
+	}
+	addr
+}
+
+// Name of a callee without r2's flag prefixes, e.g. strlen for sym.imp.strlen
+fn callee_name(target: &str) -> String {
+	target.rsplit('.').next().unwrap_or(target).to_string()
+}
+
+// Depth of nested calls after an instruction of kind, None if it returns from the traced fcn.
+// Calls that were not taken, e.g. conditional ones, do not count.
+fn call_depth(depth: usize, kind: &str, taken: bool) -> Option<usize> {
+	match kind {
+		"ret" if depth == 0 => None,
+		"ret" => Some(depth - 1),
+		kind if kind.ends_with("call") && taken => Some(depth + 1),
+		_ => Some(depth),
+	}
+}
+
+#[test]
+fn test_call_depth() {
+	// A non-stubbed inner call returns first, then the traced fcn
+	let trace = [("call", true), ("mov", false), ("ret", false), ("ccall", false), ("add", false), ("ret", false)];
+	let mut depth = Some(0);
+	for (kind, taken) in trace.iter() {
+		assert!(depth.is_some());
+		depth = call_depth(depth.unwrap(), kind, *taken);
+	}
+	assert_eq!(depth, None);
+	assert_eq!(call_depth(0, "ucall", true), Some(1));
+}
+
+#[test]
+fn test_free_addr() {
+	assert_eq!(free_addr(&[], SCRATCH_ADDR, 0x100), SCRATCH_ADDR);
+	// A PIE image mapped at the scratch address and a stack right above it
+	let maps = [(0x1f_0000, 0x20_0fff), (0x20_1000, 0x21_0000), (0x40_0000, 0x40_ffff)];
+	assert_eq!(free_addr(&maps, SCRATCH_ADDR, 0x200), 0x21_1000);
+	assert_eq!(free_addr(&maps, 0x30_0000, 0x20_0000), 0x41_0000);
+}
+
+#[test]
+fn test_callee_name() {
+	assert_eq!(callee_name("sym.imp.strlen"), "strlen");
+	assert_eq!(callee_name("reloc.memcpy"), "memcpy");
+	assert_eq!(callee_name("abs"), "abs");
+	assert_ne!(splitmix(1), splitmix(2));
+}
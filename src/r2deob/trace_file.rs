@@ -0,0 +1,147 @@
+// Traces together with the FcnConfig that produced them, so synthesis can be rerun without r2
+extern crate serde;
+use serde::{
+	Deserialize,
+	Serialize,
+};
+
+extern crate serde_json;
+extern crate sha2;
+use sha2::{
+	Digest,
+	Sha256,
+};
+
+use std::fs;
+
+use super::{
+	engine::{
+		CallStub,
+		FcnConfig,
+		Traces,
+	},
+	R2Error,
+};
+
+pub const TRACE_FILE_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+pub struct TraceFile {
+	pub version: u32,
+	// sha256 of the target binary or raw code
+	pub binary_hash: String,
+	pub fcn_config: FcnConfig,
+	pub traces: Traces,
+}
+
+impl TraceFile {
+	pub fn new(fcn: FcnConfig, traces: Traces) -> Result<TraceFile, R2Error> {
+		// A closure can not be written, the file could not be replayed
+		for (target, stub) in fcn.call_stubs.iter() {
+			if let CallStub::Closure(_) = stub {
+				return Err(R2Error::Config(format!("closure stub for {} can not be saved to a trace file", target)))
+			}
+		}
+		Ok(TraceFile {
+			version: TRACE_FILE_VERSION,
+			binary_hash: binary_hash(&fcn)?,
+			fcn_config: fcn,
+			traces,
+		})
+	}
+
+	pub fn save(&self, path: &str) -> Result<(), R2Error> {
+		let json = serde_json::to_string_pretty(self).map_err(|e| R2Error::Io(e.to_string()))?;
+		fs::write(path, json).map_err(|e| R2Error::Io(format!("could not write {}: {}", path, e)))
+	}
+
+	pub fn load(path: &str) -> Result<TraceFile, R2Error> {
+		let json = fs::read_to_string(path).map_err(|e| R2Error::Io(format!("could not read {}: {}", path, e)))?;
+		let value: serde_json::Value = serde_json::from_str(&json).map_err(|e| R2Error::Io(format!("{}: {}", path, e)))?;
+		if value["version"] != TRACE_FILE_VERSION {
+			return Err(R2Error::Io(format!("unsupported trace file version: {}", value["version"])))
+		}
+		serde_json::from_value(value).map_err(|e| R2Error::Io(format!("{}: {}", path, e)))
+	}
+
+	// Whether the binary or raw code of fcn is the one the traces were taken from
+	pub fn matches_binary(&self, fcn: &FcnConfig) -> Result<bool, R2Error> {
+		Ok(binary_hash(fcn)? == self.binary_hash)
+	}
+}
+
+fn binary_hash(fcn: &FcnConfig) -> Result<String, R2Error> {
+	match &fcn.raw {
+		Some(raw) => Ok(hash_bytes(&raw.bytes)),
+		None => hash_file(&fcn.path),
+	}
+}
+
+pub fn hash_file(path: &str) -> Result<String, R2Error> {
+	let bytes = fs::read(path).map_err(|e| R2Error::Io(format!("could not read {}: {}", path, e)))?;
+	Ok(hash_bytes(&bytes))
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+	let mut hasher = Sha256::new();
+	hasher.input(bytes);
+	hasher.result().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_trace_file_roundtrip() {
+	use std::collections::HashMap;
+	use super::engine::{
+		RawCode,
+		StopCondition,
+		StopReason,
+	};
+	let mut inputs = HashMap::new();
+	inputs.insert("esi".to_string(), vec![1, 2]);
+	let mut outputs = HashMap::new();
+	outputs.insert("rax".to_string(), vec![2, 4]);
+	let mut widths = HashMap::new();
+	widths.insert("esi".to_string(), 4);
+	widths.insert("rax".to_string(), 8);
+	let file = TraceFile {
+		version: TRACE_FILE_VERSION,
+		binary_hash: String::new(),
+		fcn_config: FcnConfig {
+			path: "calc".to_string(),
+			raw: None,
+			loc: "sym.calc".to_string(),
+			analysis: Default::default(),
+			r2_script: None,
+			snapshot: None,
+			stop: StopCondition::Ret,
+			max_steps: 64,
+			check_determinism: true,
+			n_tracers: 1,
+			input_regs: vec!["esi".to_string()],
+			input_strategy: Default::default(),
+			seed: Some(1),
+			call_stubs: HashMap::new(),
+			output_regs: vec!["rax".to_string()],
+			probes: Vec::new(),
+		},
+		traces: Traces { inputs, outputs, stops: vec![StopReason::Ret, StopReason::Address(0x1156)], widths, flags: Default::default(), stubs: Vec::new() },
+	};
+	let path = ::std::env::temp_dir().join("r2deob_test_traces.json");
+	let path = path.to_str().unwrap();
+	file.save(path).unwrap();
+	let loaded = TraceFile::load(path).unwrap();
+	assert_eq!(loaded.traces.outputs["rax"], vec![2, 4]);
+	assert_eq!(loaded.traces.stops[1], StopReason::Address(0x1156));
+	assert_eq!(loaded.fcn_config.loc, "sym.calc");
+	assert_eq!(loaded.traces.width("esi"), 4);
+	let _ = fs::remove_file(path);
+
+	let mut fcn = loaded.fcn_config.clone();
+	fcn.raw = Some(RawCode::from_hex("c3", "x86", 64, false, 0).unwrap());
+	let file = TraceFile::new(fcn.clone(), loaded.traces.clone()).unwrap();
+	assert!(file.matches_binary(&fcn).unwrap());
+	fcn.raw = Some(RawCode::from_hex("90 c3", "x86", 64, false, 0).unwrap());
+	assert!(!file.matches_binary(&fcn).unwrap());
+	fcn.call_stubs.insert("sym.checksum".to_string(), CallStub::Closure(::std::sync::Arc::new(|args| args[0])));
+	assert!(TraceFile::new(fcn, loaded.traces).is_err());
+}
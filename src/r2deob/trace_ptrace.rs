@@ -0,0 +1,498 @@
+// Native trace backend for Linux x86-64. The target binary is executed under ptrace,
+// it has to reach `loc` on its own. Inputs are set when the entry breakpoint hits.
+extern crate libc;
+
+use std::{
+	collections::HashMap,
+	env,
+	ffi::CString,
+	fs,
+	mem,
+	ptr,
+	sync::mpsc,
+	thread,
+	time::Duration,
+};
+
+use super::{
+	engine::{
+		r2_cmdj,
+		resolve_addr,
+		resolve_stop_addrs,
+		spawn,
+		FcnConfig,
+		StopCondition,
+		StopReason,
+		Trace,
+		Tracer,
+	},
+	input::mask,
+	location::Location,
+	R2Error,
+	BaseT,
+};
+
+// personality(2) flag, not exported by all libc versions
+const ADDR_NO_RANDOMIZE: libc::c_ulong = 0x0040000;
+
+// Pointer outputs get scratch memory this far below the stack pointer at fcn entry
+const SCRATCH_OFFSET: u64 = 0x4000;
+const SCRATCH_SLOT: u64 = 0x100;
+
+// The target is killed if a run takes longer, e.g. when it blocks in a syscall
+const RUN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// x86-64 general purpose registers with their 32, 16 and 8 bit aliases
+const REGISTERS: [[&str; 4]; 16] = [
+	["rax", "eax", "ax", "al"],
+	["rbx", "ebx", "bx", "bl"],
+	["rcx", "ecx", "cx", "cl"],
+	["rdx", "edx", "dx", "dl"],
+	["rsi", "esi", "si", "sil"],
+	["rdi", "edi", "di", "dil"],
+	["rbp", "ebp", "bp", "bpl"],
+	["rsp", "esp", "sp", "spl"],
+	["r8", "r8d", "r8w", "r8b"],
+	["r9", "r9d", "r9w", "r9b"],
+	["r10", "r10d", "r10w", "r10b"],
+	["r11", "r11d", "r11w", "r11b"],
+	["r12", "r12d", "r12w", "r12b"],
+	["r13", "r13d", "r13w", "r13b"],
+	["r14", "r14d", "r14w", "r14b"],
+	["r15", "r15d", "r15w", "r15b"],
+];
+
+// Status flags and their bit in eflags
+const FLAGS: [(&str, u32); 7] = [
+	("cf", 0),
+	("pf", 2),
+	("af", 4),
+	("zf", 6),
+	("sf", 7),
+	("df", 10),
+	("of", 11),
+];
+
+pub struct PtraceTracer {
+	path: CString,
+	image: String,
+	pic: bool,
+	baddr: u64,
+	entry: u64,
+	stop: StopCondition,
+	stop_addrs: Vec<u64>,
+	max_steps: usize,
+	symbols: HashMap<String,u64>,
+}
+
+impl Tracer for PtraceTracer {
+	// Use r2 once to resolve all static addresses, analysis is skipped
+	fn init(fcn: &FcnConfig, inputs: &[Location], outputs: &[Location]) -> Result<PtraceTracer, R2Error> {
+		if fcn.raw.is_some() {
+			return Err(R2Error::Config("raw code can not be executed natively".to_string()))
+		}
+		if !fcn.probes.is_empty() {
+			return Err(R2Error::Config("probes need the ESIL tracer".to_string()))
+		}
+		if fcn.snapshot.is_some() {
+			return Err(R2Error::Config("snapshots need the ESIL tracer".to_string()))
+		}
+		if !fcn.call_stubs.is_empty() {
+			return Err(R2Error::Config("call stubs need the ESIL tracer".to_string()))
+		}
+		for location in inputs.iter().chain(outputs) {
+			match location {
+				Location::Register(reg) if reg_alias(reg).is_none() && flag_bit(reg).is_none() => return Err(R2Error::UnknownRegister(reg.clone())),
+				Location::Pointer(reg, _) if reg_alias(reg).is_none() => return Err(R2Error::UnknownRegister(reg.clone())),
+				_ => {},
+			}
+		}
+
+		let mut r2pipe = spawn(&fcn.path)?;
+		// The script may define flags used as locations
+		if let Some(script) = &fcn.r2_script {
+			script.run(&mut r2pipe)?;
+		}
+		let info = r2_cmdj(&mut r2pipe, "ij")?;
+		let entry = resolve_addr(&mut r2pipe, &fcn.loc)?;
+		let stop_addrs = resolve_stop_addrs(&mut r2pipe, &fcn.stop)?;
+		let mut symbols = HashMap::new();
+		for location in inputs.iter().chain(outputs) {
+			if let Location::Memory(addr, _) = location {
+				let (base, _) = Location::split_addr(addr);
+				if reg_alias(base).is_none() {
+					symbols.insert(base.to_string(), resolve_addr(&mut r2pipe, base)?);
+				}
+			}
+		}
+		r2pipe.close();
+
+		let image = match fs::canonicalize(&fcn.path) {
+			Ok(image) => image.to_string_lossy().to_string(),
+			Err(err) => return Err(R2Error::Exec(format!("{}: {}", fcn.path, err))),
+		};
+		let path = if let Ok(path) = CString::new(fcn.path.clone()) { path }
+		else { return Err(R2Error::Config(format!("invalid path: {}", fcn.path))) };
+
+		Ok(PtraceTracer {
+			path,
+			image,
+			pic: info["bin"]["pic"].as_bool().unwrap_or(false),
+			baddr: info["bin"]["baddr"].as_u64().unwrap_or(0),
+			entry,
+			stop: fcn.stop.clone(),
+			stop_addrs,
+			max_steps: fcn.max_steps,
+			symbols,
+		})
+	}
+
+	fn trace(&mut self, inputs: &[Location], values: &[BaseT], outputs: &[Location]) -> Result<Trace, R2Error> {
+		let pid = self.spawn().map_err(R2Error::Exec)?;
+		// The child is only reaped after the watchdog is joined, so it can not kill a reused pid
+		let (done, timeout) = mpsc::channel::<()>();
+		let watchdog = thread::spawn(move || {
+			let expired = timeout.recv_timeout(RUN_TIMEOUT) == Err(mpsc::RecvTimeoutError::Timeout);
+			if expired { unsafe { libc::kill(pid, libc::SIGKILL); } }
+			expired
+		});
+		let result = self.run(pid, inputs, values, outputs);
+		drop(done);
+		let expired = watchdog.join().unwrap_or(false);
+		unsafe {
+			libc::kill(pid, libc::SIGKILL);
+			libc::waitpid(pid, ptr::null_mut(), 0);
+		}
+		if expired {
+			return Err(R2Error::Exec(format!("target killed after {}s", RUN_TIMEOUT.as_secs())))
+		}
+		result.map_err(R2Error::Exec)
+	}
+
+	fn width(&self, location: &Location) -> usize {
+		match location {
+			Location::Register(reg) if flag_bit(reg).is_some() => 1,
+			Location::Register(reg) => reg_alias(reg).map_or(8, |(_, size)| size),
+			_ => location.width(),
+		}
+	}
+
+	fn is_flag(&self, location: &Location) -> bool {
+		match location {
+			Location::Register(reg) => flag_bit(reg).is_some(),
+			_ => false,
+		}
+	}
+}
+
+impl PtraceTracer {
+	// Fork and exec the target, returns once the tracee stopped at exec.
+	// stdin and stdout go to /dev/null. Symbols are bound at load time, so
+	// lazy binding does not count against max_steps.
+	fn spawn(&self) -> Result<libc::pid_t, String> {
+		let argv = [self.path.as_ptr(), ptr::null()];
+		let vars: Vec<CString> = env::vars()
+			.filter(|(key, _)| key != "LD_BIND_NOW")
+			.chain(Some(("LD_BIND_NOW".to_string(), "1".to_string())))
+			.filter_map(|(key, value)| CString::new(format!("{}={}", key, value)).ok())
+			.collect();
+		let mut envp: Vec<*const libc::c_char> = vars.iter().map(|var| var.as_ptr()).collect();
+		envp.push(ptr::null());
+		let devnull = CString::new("/dev/null").unwrap();
+		let pid = unsafe { libc::fork() };
+		if pid == 0 {
+			unsafe {
+				let fd = libc::open(devnull.as_ptr(), libc::O_RDWR);
+				if fd >= 0 {
+					libc::dup2(fd, 0);
+					libc::dup2(fd, 1);
+				}
+				libc::ptrace(libc::PTRACE_TRACEME, 0, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>());
+				libc::personality(ADDR_NO_RANDOMIZE);
+				libc::execve(self.path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+				libc::_exit(127);
+			}
+		}
+		if pid < 0 {
+			return Err("fork failed".to_string())
+		}
+		wait_trap(pid)?;
+		Ok(pid)
+	}
+
+	fn run(&self, pid: libc::pid_t, inputs: &[Location], values: &[BaseT], outputs: &[Location]) -> Result<Trace, String> {
+		let base = self.load_base(pid)?;
+		// Run to fcn entry
+		let entry = self.rebase(self.entry, base);
+		let orig = set_breakpoint(pid, entry)?;
+		cont(pid)?;
+		wait_trap(pid)?;
+		poke(pid, entry, orig)?;
+		let mut regs = get_regs(pid)?;
+		if regs.rip != entry + 1 {
+			return Err(format!("unexpected trap at 0x{:x}", regs.rip - 1))
+		}
+		regs.rip = entry;
+		// Set input
+		for (location, value) in inputs.iter().zip(values) {
+			if let Location::Register(reg) = location {
+				write_reg(&mut regs, reg, *value as u64)?;
+			}
+		}
+		for (location, value) in inputs.iter().zip(values) {
+			if let Location::Memory(addr, size) = location {
+				let addr = self.resolve_mem_addr(&regs, addr, base)?;
+				write_mem(pid, addr, *size, *value as u64)?;
+			}
+		}
+		// Fix memory output addresses at fcn entry
+		let mut output_addrs = Vec::new();
+		for (n, location) in outputs.iter().enumerate() {
+			match location {
+				Location::Register(_) => output_addrs.push(None),
+				Location::Memory(addr, _) => output_addrs.push(Some(self.resolve_mem_addr(&regs, addr, base)?)),
+				Location::Pointer(reg, _) => {
+					let addr = regs.rsp - SCRATCH_OFFSET - SCRATCH_SLOT * (n as u64 + 1);
+					for offset in (0..SCRATCH_SLOT).step_by(8) {
+						poke(pid, addr + offset, 0)?;
+					}
+					write_reg(&mut regs, reg, addr)?;
+					output_addrs.push(Some(addr));
+				},
+			}
+		}
+		set_regs(pid, &regs)?;
+		// Single step to the stop point, for ret that is the return address on the stack
+		let stops: Vec<u64> = match self.stop {
+			StopCondition::Ret => vec![peek(pid, regs.rsp)?],
+			_ => self.stop_addrs.iter().map(|addr| self.rebase(*addr, base)).collect(),
+		};
+		let mut steps = 0;
+		let (stop, regs) = loop {
+			let regs = get_regs(pid)?;
+			match (&self.stop, stops.iter().position(|addr| *addr == regs.rip)) {
+				(StopCondition::Ret, Some(_)) => break (StopReason::Ret, regs),
+				(_, Some(n)) => break (StopReason::Address(self.stop_addrs[n]), regs),
+				_ if steps >= self.max_steps => break (StopReason::MaxSteps, regs),
+				_ => {},
+			}
+			step(pid)?;
+			wait_trap(pid)?;
+			steps += 1;
+		};
+		// Fetch results
+		let mut result = Vec::new();
+		for (location, addr) in outputs.iter().zip(output_addrs) {
+			match (location, addr) {
+				(Location::Register(reg), _) => result.push(read_reg(&regs, reg)? as BaseT),
+				(Location::Memory(_, size), Some(addr)) | (Location::Pointer(_, size), Some(addr)) => {
+					result.push(read_mem(pid, addr, *size)? as BaseT);
+				},
+				_ => return Err(format!("no address for output: {}", location)),
+			}
+		}
+		Ok(Trace { outputs: result, stop, stubs: Vec::new(), probes: Vec::new() })
+	}
+
+	// Lowest mapping of the target image
+	fn load_base(&self, pid: libc::pid_t) -> Result<u64, String> {
+		let maps = if let Ok(maps) = fs::read_to_string(format!("/proc/{}/maps", pid)) { maps }
+		else { return Err("could not read memory maps".to_string()) };
+		maps.lines()
+			.filter(|line| line.ends_with(&self.image))
+			.filter_map(|line| line.split('-').next())
+			.filter_map(|start| u64::from_str_radix(start, 16).ok())
+			.min()
+			.ok_or(format!("{} is not mapped", self.image))
+	}
+
+	fn rebase(&self, addr: u64, base: u64) -> u64 {
+		if self.pic { addr.wrapping_sub(self.baddr).wrapping_add(base) } else { addr }
+	}
+
+	fn resolve_mem_addr(&self, regs: &libc::user_regs_struct, addr: &str, base: u64) -> Result<u64, String> {
+		let (name, offset) = Location::split_addr(addr);
+		let value = if reg_alias(name).is_some() { read_reg(regs, name)? }
+		else {
+			if let Some(value) = self.symbols.get(name) { self.rebase(*value, base) }
+			else { return Err(format!("could not resolve address: {}", addr)) }
+		};
+		Ok((value as i64).wrapping_add(offset) as u64)
+	}
+}
+
+// Full register name and width in bytes
+fn reg_alias(name: &str) -> Option<(&'static str, usize)> {
+	match name {
+		"rip" => return Some(("rip", 8)),
+		"rflags" | "eflags" => return Some(("eflags", 8)),
+		_ => {},
+	}
+	for names in REGISTERS.iter() {
+		for (n, alias) in names.iter().enumerate() {
+			if *alias == name {
+				return Some((names[0], 8 >> n))
+			}
+		}
+	}
+	None
+}
+
+fn flag_bit(name: &str) -> Option<u32> {
+	FLAGS.iter().find(|(flag, _)| *flag == name).map(|(_, bit)| *bit)
+}
+
+fn reg_mut<'a>(regs: &'a mut libc::user_regs_struct, name: &str) -> Option<&'a mut u64> {
+	match name {
+		"rax" => Some(&mut regs.rax),
+		"rbx" => Some(&mut regs.rbx),
+		"rcx" => Some(&mut regs.rcx),
+		"rdx" => Some(&mut regs.rdx),
+		"rsi" => Some(&mut regs.rsi),
+		"rdi" => Some(&mut regs.rdi),
+		"rbp" => Some(&mut regs.rbp),
+		"rsp" => Some(&mut regs.rsp),
+		"r8" => Some(&mut regs.r8),
+		"r9" => Some(&mut regs.r9),
+		"r10" => Some(&mut regs.r10),
+		"r11" => Some(&mut regs.r11),
+		"r12" => Some(&mut regs.r12),
+		"r13" => Some(&mut regs.r13),
+		"r14" => Some(&mut regs.r14),
+		"r15" => Some(&mut regs.r15),
+		"rip" => Some(&mut regs.rip),
+		"eflags" => Some(&mut regs.eflags),
+		_ => None,
+	}
+}
+
+fn read_reg(regs: &libc::user_regs_struct, name: &str) -> Result<u64, String> {
+	if let Some(bit) = flag_bit(name) {
+		return Ok((regs.eflags >> bit) & 1)
+	}
+	let mut regs = *regs;
+	if let Some((full, size)) = reg_alias(name) {
+		if let Some(value) = reg_mut(&mut regs, full) {
+			return Ok(*value & mask(size))
+		}
+	}
+	Err(format!("unknown register: {}", name))
+}
+
+// 32 bit writes clear the upper half like on the CPU, 16 and 8 bit writes merge
+fn write_reg(regs: &mut libc::user_regs_struct, name: &str, value: u64) -> Result<(), String> {
+	if let Some(bit) = flag_bit(name) {
+		regs.eflags = (regs.eflags & !(1 << bit)) | ((value & 1) << bit);
+		return Ok(())
+	}
+	if let Some((full, size)) = reg_alias(name) {
+		if let Some(reg) = reg_mut(regs, full) {
+			*reg = match size {
+				8 | 4 => value & mask(size),
+				_ => (*reg & !mask(size)) | (value & mask(size)),
+			};
+			return Ok(())
+		}
+	}
+	Err(format!("unknown register: {}", name))
+}
+
+fn wait_trap(pid: libc::pid_t) -> Result<(), String> {
+	let mut status = 0;
+	unsafe {
+		libc::waitpid(pid, &mut status, 0);
+		if libc::WIFSTOPPED(status) && libc::WSTOPSIG(status) == libc::SIGTRAP {
+			return Ok(())
+		}
+		if libc::WIFSTOPPED(status) {
+			return Err(format!("target stopped with signal {}", libc::WSTOPSIG(status)))
+		}
+		if libc::WIFSIGNALED(status) {
+			return Err(format!("target killed by signal {}", libc::WTERMSIG(status)))
+		}
+	}
+	Err("target exited before reaching the stop point".to_string())
+}
+
+fn cont(pid: libc::pid_t) -> Result<(), String> {
+	let res = unsafe { libc::ptrace(libc::PTRACE_CONT, pid, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>()) };
+	if res < 0 { return Err("PTRACE_CONT failed".to_string()) }
+	Ok(())
+}
+
+fn step(pid: libc::pid_t) -> Result<(), String> {
+	let res = unsafe { libc::ptrace(libc::PTRACE_SINGLESTEP, pid, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>()) };
+	if res < 0 { return Err("PTRACE_SINGLESTEP failed".to_string()) }
+	Ok(())
+}
+
+fn get_regs(pid: libc::pid_t) -> Result<libc::user_regs_struct, String> {
+	let mut regs: libc::user_regs_struct = unsafe { mem::zeroed() };
+	let res = unsafe { libc::ptrace(libc::PTRACE_GETREGS, pid, ptr::null_mut::<libc::c_void>(), &mut regs as *mut _ as *mut libc::c_void) };
+	if res < 0 { return Err("PTRACE_GETREGS failed".to_string()) }
+	Ok(regs)
+}
+
+fn set_regs(pid: libc::pid_t, regs: &libc::user_regs_struct) -> Result<(), String> {
+	let res = unsafe { libc::ptrace(libc::PTRACE_SETREGS, pid, ptr::null_mut::<libc::c_void>(), regs as *const _ as *mut libc::c_void) };
+	if res < 0 { return Err("PTRACE_SETREGS failed".to_string()) }
+	Ok(())
+}
+
+fn peek(pid: libc::pid_t, addr: u64) -> Result<u64, String> {
+	unsafe {
+		*libc::__errno_location() = 0;
+		let word = libc::ptrace(libc::PTRACE_PEEKDATA, pid, addr as *mut libc::c_void, ptr::null_mut::<libc::c_void>());
+		if word == -1 && *libc::__errno_location() != 0 {
+			return Err(format!("could not read memory at 0x{:x}", addr))
+		}
+		Ok(word as u64)
+	}
+}
+
+fn poke(pid: libc::pid_t, addr: u64, word: u64) -> Result<(), String> {
+	let res = unsafe { libc::ptrace(libc::PTRACE_POKEDATA, pid, addr as *mut libc::c_void, word as *mut libc::c_void) };
+	if res < 0 { return Err(format!("could not write memory at 0x{:x}", addr)) }
+	Ok(())
+}
+
+fn read_mem(pid: libc::pid_t, addr: u64, size: usize) -> Result<u64, String> {
+	Ok(peek(pid, addr)? & mask(size))
+}
+
+fn write_mem(pid: libc::pid_t, addr: u64, size: usize, value: u64) -> Result<(), String> {
+	let word = peek(pid, addr)?;
+	poke(pid, addr, (word & !mask(size)) | (value & mask(size)))
+}
+
+// Returns the original word to restore later
+fn set_breakpoint(pid: libc::pid_t, addr: u64) -> Result<u64, String> {
+	let word = peek(pid, addr)?;
+	poke(pid, addr, (word & !0xff) | 0xcc)?;
+	Ok(word)
+}
+
+#[test]
+fn test_reg_alias() {
+	assert_eq!(reg_alias("rax"), Some(("rax", 8)));
+	assert_eq!(reg_alias("esi"), Some(("rsi", 4)));
+	assert_eq!(reg_alias("r9w"), Some(("r9", 2)));
+	assert_eq!(reg_alias("dil"), Some(("rdi", 1)));
+	assert_eq!(reg_alias("xmm0"), None);
+}
+
+#[test]
+fn test_write_reg() {
+	let mut regs: libc::user_regs_struct = unsafe { mem::zeroed() };
+	regs.rax = 0xffff_ffff_ffff_ffff;
+	write_reg(&mut regs, "al", 0x12).unwrap();
+	assert_eq!(regs.rax, 0xffff_ffff_ffff_ff12);
+	write_reg(&mut regs, "eax", 0x1234_5678_9abc).unwrap();
+	assert_eq!(regs.rax, 0x5678_9abc);
+	assert_eq!(read_reg(&regs, "ax").unwrap(), 0x9abc);
+	regs.eflags = 0x202;
+	write_reg(&mut regs, "zf", 1).unwrap();
+	assert_eq!(regs.eflags, 0x242);
+	assert_eq!(read_reg(&regs, "zf").unwrap(), 1);
+	assert_eq!(read_reg(&regs, "cf").unwrap(), 0);
+}